@@ -0,0 +1,37 @@
+use pcp::{Alert, AsyncClient, InboundMap, ProtocolNumber, RequestType};
+use std::net::Ipv4Addr;
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let pcp = AsyncClient::<Ipv4Addr>::start(
+        [192, 168, 1, 101].into(), // My address
+        [192, 168, 1, 1].into(),   // PCP server address
+    )
+    .await
+    .unwrap();
+
+    // Define a mapping that maps any incoming request on TCP port 6000 to my address
+    let map = InboundMap::new(6000, 120).protocol(ProtocolNumber::Tcp);
+
+    // Request the mapping
+    let mut handle = pcp.request(map, RequestType::KeepAlive).await.unwrap();
+
+    while let Some(alert) = handle.wait_alert().await {
+        match alert {
+            Alert::StateChange => println!("State: {:?}", handle.state()),
+            Alert::Assigned(ip, port, lifetime) => println!(
+                "Assigned ip: {:?}\nAssigned port: {}\nAssigned lifetime: {}",
+                ip, port, lifetime
+            ),
+            Alert::ServerReset => println!("The server has been reset"),
+            Alert::ExternalAddressChanged { old, new } => {
+                println!("External address changed from {} to {}", old, new)
+            }
+            Alert::ClientAddressChanged { old, new } => {
+                println!("Client address changed from {} to {}", old, new)
+            }
+            // Only the outbound mappings get it
+            Alert::PeerAssigned(_) => (),
+        }
+    }
+}
@@ -21,6 +21,15 @@ fn main() {
                 "Assigned ip: {:?}\nAssigned port: {}\nAssigned lifetime: {}",
                 ip, port, lifetime
             ),
+            Alert::ServerReset => println!("The server has been reset"),
+            Alert::ExternalAddressChanged { old, new } => {
+                println!("External address changed from {} to {}", old, new)
+            }
+            Alert::ClientAddressChanged { old, new } => {
+                println!("Client address changed from {} to {}", old, new)
+            }
+            // Only the outbound mappings get it
+            Alert::PeerAssigned(_) => (),
         }
     }
 }
@@ -23,6 +23,16 @@ fn main() {
                 "Assigned ip: {:?}\nAssigned port: {}\nAssigned lifetime: {}",
                 ip, port, lifetime,
             ),
+            Alert::ServerReset => println!("The server has been reset"),
+            Alert::ExternalAddressChanged { old, new } => {
+                println!("External address changed from {} to {}", old, new)
+            }
+            Alert::ClientAddressChanged { old, new } => {
+                println!("Client address changed from {} to {}", old, new)
+            }
+            Alert::PeerAssigned(peer) => {
+                println!("Connected to {} from {}", peer.remote(), peer.external())
+            }
         }
     }
 }
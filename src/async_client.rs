@@ -0,0 +1,627 @@
+//! The `AsyncClient` is the asynchronous counterpart of the `Client`: instead of
+//! using threads and blocking sockets it drives the `PcpSession` from a tokio task,
+//! using the tokio sockets and timers.
+//!
+//! This module is available only with the `tokio` feature.
+
+use super::builder::{bind_address, ClientBuilder};
+use super::client::Rebind;
+use super::discovery;
+use super::handle::{Error, RequestType, ShutdownReport};
+use super::map::{Map, Mapping};
+#[cfg(all(feature = "netlink", target_os = "linux"))]
+use super::netlink;
+use super::session::{PcpSession, ServerStatus, SessionEvent};
+use super::slab::MappingId;
+use super::state::{Alert, AtomicState, State};
+use super::store::{Persistence, StoredMapping};
+use super::IpAddress;
+use futures_core::Stream;
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::mpsc::RecvError;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, oneshot};
+
+/// Commands that the client task has to process
+enum Command<Ip: IpAddress> {
+    /// The handle requests a mapping, the id of the mapping is sent back through the oneshot
+    /// channel
+    Map(
+        Mapping<Ip>,
+        RequestType,
+        Arc<AtomicState>,
+        oneshot::Sender<MappingId>,
+        mpsc::UnboundedSender<Alert>,
+    ),
+    /// The handle of the mapping requests to revoke a mapping
+    Revoke(MappingId),
+    /// The handle of the mapping requests to renew a mapping for the specified lifetime
+    Renew(MappingId, u32),
+    /// The handle of the mapping has been dropped
+    Drop(MappingId),
+    /// The handle requests to send an announce request, the status of the server (if it
+    /// responds) is sent back through the oneshot channel
+    Announce(oneshot::Sender<Option<ServerStatus>>),
+    /// The handle enables or disables the health-check with the specified interval
+    HealthCheck(Option<Duration>),
+    /// The handle requests the mappings of the previous run, which are sent back through the
+    /// oneshot channel
+    Restore(oneshot::Sender<Vec<StoredMapping<Ip>>>),
+    /// The addresses or the routes of the system have changed (see the `netlink` module)
+    NetworkChange,
+    /// The handle requests to delete the mappings, waiting for the confirmations for at most
+    /// the specified amount of time, and then to shutdown the service. The outcome of the
+    /// deletions is sent back through the oneshot channel
+    GracefulShutdown(Duration, oneshot::Sender<ShutdownReport<Ip>>),
+    /// The handle of the client has dropped, the service has to shutdown immediately
+    Shutdown,
+}
+
+/// The channels that connect a mapping to its `AsyncMapHandle`
+struct MapLink {
+    state: Arc<AtomicState>,
+    to_handle: mpsc::UnboundedSender<Alert>,
+}
+
+/// The state of the task that drives the `PcpSession`
+struct Driver<Ip: IpAddress> {
+    /// Socket used to talk with the PCP server
+    socket: UdpSocket,
+    /// Addresses of the candidate servers, indexed like in the session
+    servers: Vec<SocketAddr>,
+    /// Socket listening for the announcements of the server, if enabled
+    announce: Option<UdpSocket>,
+    /// Address the sockets are bound to
+    bound: Ip,
+    /// Function that binds the sockets again, when the network is watched
+    rebind: Option<Rebind<Ip>>,
+    /// Receiver where the commands come from
+    commands: mpsc::UnboundedReceiver<Command<Ip>>,
+    /// Sender connected to this client's handle, used for notifying eventual errors
+    to_handle: mpsc::UnboundedSender<Error>,
+    /// The protocol state machine
+    session: PcpSession<Ip>,
+    /// Where the mappings are saved, if they are
+    persistence: Option<Persistence<Ip>>,
+    /// Channels of each mapping, indexed by the id of the mapping
+    links: HashMap<MappingId, MapLink>,
+    /// Channels of the handles waiting for the response to the announce request
+    announces: Vec<oneshot::Sender<Option<ServerStatus>>>,
+    /// Mappings whose deletion has been confirmed by the server
+    deleted: Vec<MappingId>,
+}
+
+impl<Ip: IpAddress> Driver<Ip> {
+    /// Forwards the notifications of the session to the handles and sends the queued datagrams,
+    /// then saves the mappings if they have changed
+    async fn flush(&mut self) {
+        while let Some(event) = self.session.poll_event() {
+            match event {
+                SessionEvent::StateChange(id, state) => {
+                    if let Some(link) = self.links.get(&id) {
+                        link.state.set(state);
+                        link.to_handle.send(Alert::StateChange).ok();
+                    }
+                }
+                SessionEvent::Alert(id, alert) => {
+                    if let Some(link) = self.links.get(&id) {
+                        link.to_handle.send(alert).ok();
+                    }
+                }
+                SessionEvent::Deleted(id) => self.deleted.push(id),
+                SessionEvent::Unmatched(response) => {
+                    self.to_handle.send(Error::UnmatchedResponse(response)).ok();
+                }
+                SessionEvent::ServerReset => {
+                    for link in self.links.values() {
+                        link.to_handle.send(Alert::ServerReset).ok();
+                    }
+                }
+                // Nobody is waiting for the health-check, the failure is reported as an error
+                SessionEvent::Announced(None) if self.announces.is_empty() => {
+                    self.to_handle.send(Error::NoResponse).ok();
+                }
+                SessionEvent::Announced(status) => {
+                    for tx in self.announces.drain(..) {
+                        tx.send(status).ok();
+                    }
+                }
+            }
+        }
+        while let Some((server, datagram)) = self.session.poll_transmit() {
+            // The network might be unreachable for a while, the requests that didn't reach the
+            // server are retransmitted by the session like the lost ones
+            if let Err(err) = self.socket.send_to(&datagram, self.servers[server]).await {
+                self.to_handle.send(Error::Send(err)).ok();
+            }
+        }
+        if let Some(persistence) = &mut self.persistence {
+            if let Err(err) = persistence.save(&self.session, Instant::now()) {
+                self.to_handle.send(Error::Store(err)).ok();
+            }
+        }
+    }
+
+    /// Processes a datagram, the ones that don't come from the servers are discarded and a
+    /// parsing error is only reported to the handle
+    fn datagram(&mut self, from: SocketAddr, data: &[u8]) {
+        let server = match self.servers.iter().position(|s| s.ip() == from.ip()) {
+            Some(server) => server,
+            None => return,
+        };
+        if let Err(err) = self.session.handle_datagram(server, data, Instant::now()) {
+            self.to_handle.send(err.into()).ok();
+        }
+    }
+
+    /// The network has changed: the address used to reach each server is looked up again and,
+    /// if the sockets are bound to an address that isn't the right one anymore, they are bound
+    /// again
+    fn network_changed(&mut self) {
+        let now = Instant::now();
+        for (server, &addr) in self.servers.iter().enumerate() {
+            // Without a route to the server the old address is as good as any other
+            if let Some(client) = discovery::source(addr).ok().and_then(Ip::from_ip) {
+                self.session.set_address(server, client, now);
+            }
+        }
+        let servers = 0..self.servers.len();
+        let bound = bind_address(servers.filter_map(|s| self.session.address(s)));
+        if Into::<IpAddr>::into(bound) != self.bound.into() {
+            if let Err(err) = self.rebind(bound) {
+                self.to_handle.send(err.into()).ok();
+            }
+        }
+    }
+
+    /// Replaces the sockets with new ones bound to the specified address
+    fn rebind(&mut self, client: Ip) -> io::Result<()> {
+        let (socket, announce) = match &self.rebind {
+            Some(rebind) => rebind(client)?,
+            None => return Ok(()),
+        };
+        self.socket = into_tokio(socket)?;
+        self.announce = announce.map(into_tokio).transpose()?;
+        self.bound = client;
+        Ok(())
+    }
+
+    /// Processes a command, returns `false` when the client has to stop
+    fn command(&mut self, command: Command<Ip>) -> bool {
+        let now = Instant::now();
+        match command {
+            Command::Map(map, kind, state, handle_id, to_handle) => {
+                let id = self.session.request(map, kind, now);
+                self.links.insert(id, MapLink { state, to_handle });
+                handle_id.send(id).ok();
+            }
+            Command::Revoke(id) => self.session.revoke(id, now),
+            Command::Renew(id, lifetime) => self.session.renew(id, lifetime, now),
+            Command::Drop(id) => {
+                self.links.remove(&id);
+                self.session.remove(id, now)
+            }
+            Command::Announce(tx) => {
+                self.announces.push(tx);
+                self.session.announce(now)
+            }
+            Command::HealthCheck(interval) => self.session.set_health_check(interval, now),
+            Command::Restore(tx) => {
+                let previous = match &mut self.persistence {
+                    Some(persistence) => persistence.restore(now),
+                    None => Vec::new(),
+                };
+                tx.send(previous).ok();
+            }
+            Command::NetworkChange => self.network_changed(),
+            // Handled by `run`, as it has to wait for the server
+            Command::GracefulShutdown(..) => (),
+            Command::Shutdown => return false,
+        }
+        true
+    }
+
+    /// Runs the client until it's shut down or all the handles are dropped, the errors are sent
+    /// to the handle
+    async fn run(mut self) {
+        let mut buf = [0; 1011];
+        let mut announce_buf = [0; 1011];
+        loop {
+            self.session.handle_timeout(Instant::now());
+            self.flush().await;
+
+            let deadline = self.session.poll_timeout();
+            let timeout = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline.into()).await,
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                Ok((bytes, from)) = self.socket.recv_from(&mut buf) => {
+                    self.datagram(from, &buf[..bytes])
+                }
+                Ok((bytes, from)) = recv_from(self.announce.as_ref(), &mut announce_buf) => {
+                    self.datagram(from, &announce_buf[..bytes])
+                }
+                command = self.commands.recv() => match command {
+                    Some(Command::GracefulShutdown(timeout, tx)) => {
+                        let report = self.close(timeout).await;
+                        tx.send(report).ok();
+                        return;
+                    }
+                    Some(command) => {
+                        if !self.command(command) {
+                            return;
+                        }
+                    }
+                    // All the handles have been dropped
+                    None => return,
+                },
+                _ = timeout => (),
+            }
+        }
+    }
+
+    /// Deletes all the mappings and waits for the server to confirm the deletions, for at most
+    /// the specified amount of time. Meanwhile only the datagrams are processed
+    async fn close(&mut self, timeout: Duration) -> ShutdownReport<Ip> {
+        let deadline = Instant::now() + timeout;
+        let deleted = self.session.remove_all(Instant::now());
+        let mut buf = [0; 1011];
+        let mut announce_buf = [0; 1011];
+        loop {
+            let now = Instant::now();
+            self.session.handle_timeout(now);
+            self.flush().await;
+            // The mappings are forgotten once the deletion is confirmed or given up on
+            let pending = deleted
+                .iter()
+                .any(|&(id, _)| self.session.state(id).is_some());
+            if !pending || now >= deadline {
+                break;
+            }
+            let wake = match self.session.poll_timeout() {
+                Some(timeout) => timeout.min(deadline),
+                None => deadline,
+            };
+            tokio::select! {
+                Ok((bytes, from)) = self.socket.recv_from(&mut buf) => {
+                    self.datagram(from, &buf[..bytes])
+                }
+                Ok((bytes, from)) = recv_from(self.announce.as_ref(), &mut announce_buf) => {
+                    self.datagram(from, &announce_buf[..bytes])
+                }
+                _ = tokio::time::sleep_until(wake.into()) => (),
+            }
+        }
+        ShutdownReport::new(deleted, &self.deleted)
+    }
+}
+
+/// An asynchronous PCP client running on a tokio task.
+///
+/// It works like the `Client` (and like it it operates on a single stack) but
+/// mappings are requested with an `async` method and their alerts can be
+/// consumed as a `Stream`.
+///
+/// # Examples
+///
+/// Request an inbound mapping and wait for its alerts:
+/**
+
+    use futures::StreamExt;
+
+    let client = AsyncClient::<Ipv4Addr>::start(client, server).await.unwrap();
+
+    let map = InboundMap::new(6000, 120).protocol(ProtocolNumber::Tcp);
+    let mut map_handle = client.request(map, RequestType::KeepAlive).await.unwrap();
+
+    while let Some(alert) = map_handle.next().await {
+        println!("{:?}", alert);
+    }
+*/
+pub struct AsyncClient<Ip: IpAddress> {
+    to_client: mpsc::UnboundedSender<Command<Ip>>,
+    from_client: mpsc::UnboundedReceiver<Error>,
+    /// Task of the client, which is awaited on shutdown
+    task: Option<tokio::task::JoinHandle<()>>,
+    /// Thread following the changes of the network, if it's watched
+    #[cfg(all(feature = "netlink", target_os = "linux"))]
+    watcher: Option<netlink::Watcher>,
+}
+
+impl<Ip: IpAddress> AsyncClient<Ip> {
+    /// Starts the PCP client that drives the session with the socket used to talk with the
+    /// servers (whose addresses are in the same order of the session) and the one listening for
+    /// announcements (if any), see `ClientBuilder::start_async`. The task of the client is
+    /// spawned on the current tokio runtime
+    pub(crate) fn launch(
+        socket: std::net::UdpSocket,
+        announce: Option<std::net::UdpSocket>,
+        servers: Vec<SocketAddr>,
+        rebind: Option<Rebind<Ip>>,
+        session: PcpSession<Ip>,
+        persistence: Option<Persistence<Ip>>,
+    ) -> io::Result<Self> {
+        let socket = into_tokio(socket)?;
+        let announce = announce.map(into_tokio).transpose()?;
+        let (to_client, commands) = mpsc::unbounded_channel();
+        let (to_handle, from_client) = mpsc::unbounded_channel();
+
+        #[cfg(all(feature = "netlink", target_os = "linux"))]
+        let watcher = match rebind {
+            Some(_) => {
+                let (tx, errors) = (to_client.clone(), to_handle.clone());
+                let notify = move |change: io::Result<_>| match change {
+                    Ok(_) => tx.send(Command::NetworkChange).is_ok(),
+                    Err(err) => errors.send(Error::Watch(err)).is_ok(),
+                };
+                Some(netlink::watch(Ip::LENGTH == 128, notify)?)
+            }
+            None => None,
+        };
+
+        let bound = bind_address((0..servers.len()).filter_map(|s| session.address(s)));
+        let task = tokio::spawn(
+            Driver {
+                socket,
+                servers,
+                announce,
+                bound,
+                rebind,
+                commands,
+                to_handle,
+                session,
+                persistence,
+                links: HashMap::new(),
+                announces: Vec::new(),
+                deleted: Vec::new(),
+            }
+            .run(),
+        );
+        Ok(Self {
+            to_client,
+            from_client,
+            task: Some(task),
+            #[cfg(all(feature = "netlink", target_os = "linux"))]
+            watcher,
+        })
+    }
+
+    /// Sends the request to the client that will then send it to the server
+    pub async fn request<M: Map<Ip>>(
+        &self,
+        map: M,
+        kind: RequestType,
+    ) -> Result<AsyncMapHandle<Ip>, Error> {
+        let (id_tx, id_rx) = oneshot::channel();
+        let (alert_tx, alert_rx) = mpsc::unbounded_channel();
+        let state = Arc::new(AtomicState::new(State::Requested));
+        self.to_client
+            .send(Command::Map(
+                map.into(),
+                kind,
+                Arc::clone(&state),
+                id_tx,
+                alert_tx,
+            ))
+            .map_err(|_| Error::Channel(RecvError))?;
+        let id = id_rx.await.map_err(|_| Error::Channel(RecvError))?;
+        Ok(AsyncMapHandle {
+            id,
+            state,
+            to_client: self.to_client.clone(),
+            from_client: alert_rx,
+        })
+    }
+
+    /// Requests again the mappings of the previous run of the client, saved in its state file
+    /// (see `ClientBuilder::state_file`), with their nonces: the server refreshes them instead
+    /// of creating new ones. The ones that aren't needed anymore can be deleted by dropping
+    /// their handles.
+    ///
+    /// Until they are restored the mappings are kept in the file as they were, only the first
+    /// call returns them
+    pub async fn restore(&self) -> Result<Vec<AsyncMapHandle<Ip>>, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.to_client
+            .send(Command::Restore(tx))
+            .map_err(|_| Error::Channel(RecvError))?;
+        let previous = rx.await.map_err(|_| Error::Channel(RecvError))?;
+        let mut handles = Vec::with_capacity(previous.len());
+        for stored in previous {
+            handles.push(self.request(stored.mapping, stored.kind).await?);
+        }
+        Ok(handles)
+    }
+
+    /// Sends an announce request to the server and waits for its response, which tells the
+    /// version of the protocol spoken by the server and its epoch. `Error::NoResponse` is
+    /// returned if the server never responds
+    pub async fn announce(&self) -> Result<ServerStatus, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.to_client
+            .send(Command::Announce(tx))
+            .map_err(|_| Error::Channel(RecvError))?;
+        let status = rx.await.map_err(|_| Error::Channel(RecvError))?;
+        status.ok_or(Error::NoResponse)
+    }
+
+    /// Enables (or disables, with `None`) the health-check: while there are no active mappings
+    /// the client sends an announce request every `interval`, so that a reboot of the server is
+    /// detected even without mappings. If the server stops responding an `Error::NoResponse`
+    /// is reported
+    pub fn health_check(&self, interval: Option<Duration>) {
+        self.to_client.send(Command::HealthCheck(interval)).ok();
+    }
+
+    /// Waits for an error to arrive
+    pub async fn wait_err(&mut self) -> Error {
+        self.from_client
+            .recv()
+            .await
+            .unwrap_or(Error::Channel(RecvError))
+    }
+
+    /// Returns `Some(Error)` if an error has been received, `None` otherwise
+    pub fn poll_err(&mut self) -> Option<Error> {
+        self.from_client.try_recv().ok()
+    }
+
+    /// Deletes all the mappings and stops the client: the server is given up to `timeout` to
+    /// confirm the deletions, then the task of the client (and the thread watching the network,
+    /// if any) is awaited. The returned report tells which deletions have been confirmed
+    pub async fn shutdown(mut self, timeout: Duration) -> Result<ShutdownReport<Ip>, Error> {
+        let (tx, rx) = oneshot::channel();
+        self.to_client
+            .send(Command::GracefulShutdown(timeout, tx))
+            .map_err(|_| Error::Channel(RecvError))?;
+        let report = rx.await.map_err(|_| Error::Channel(RecvError));
+        if let Some(task) = self.task.take() {
+            task.await.ok();
+        }
+        #[cfg(all(feature = "netlink", target_os = "linux"))]
+        if let Some(watcher) = self.watcher.take() {
+            tokio::task::spawn_blocking(|| watcher.join()).await.ok();
+        }
+        report
+    }
+}
+
+impl<Ip: IpAddress> Drop for AsyncClient<Ip> {
+    fn drop(&mut self) {
+        self.to_client.send(Command::Shutdown).ok();
+        // The task can't be awaited here, but the thread watching the network stops shortly
+        #[cfg(all(feature = "netlink", target_os = "linux"))]
+        if let Some(watcher) = self.watcher.take() {
+            watcher.join();
+        }
+    }
+}
+
+impl AsyncClient<Ipv4Addr> {
+    /// Starts the PCP client on the current tokio runtime
+    ///
+    /// The client uses the default settings, use a `ClientBuilder` to change them.
+    pub async fn start(client: Ipv4Addr, server: Ipv4Addr) -> io::Result<Self> {
+        ClientBuilder::new(client, server).start_async().await
+    }
+
+    /// Starts the PCP client on the current tokio runtime with the specified candidate servers,
+    /// in order of preference (see `ClientBuilder::with_servers`)
+    pub async fn with_servers(servers: &[Ipv4Addr]) -> io::Result<Self> {
+        ClientBuilder::<Ipv4Addr>::with_servers(servers)?
+            .start_async()
+            .await
+    }
+
+    /// Starts the PCP client on the current tokio runtime, using the server advertised by DHCP
+    /// or the default gateway as the PCP server (only on Linux, see the `discovery` module)
+    pub async fn discover() -> io::Result<Self> {
+        ClientBuilder::<Ipv4Addr>::discover()?.start_async().await
+    }
+}
+
+impl AsyncClient<Ipv6Addr> {
+    /// Starts the PCP client on the current tokio runtime
+    ///
+    /// The client uses the default settings, use a `ClientBuilder` to change them.
+    pub async fn start(client: Ipv6Addr, server: Ipv6Addr) -> io::Result<Self> {
+        ClientBuilder::new(client, server).start_async().await
+    }
+
+    /// Starts the PCP client on the current tokio runtime with the specified candidate servers,
+    /// in order of preference (see `ClientBuilder::with_servers`)
+    pub async fn with_servers(servers: &[Ipv6Addr]) -> io::Result<Self> {
+        ClientBuilder::<Ipv6Addr>::with_servers(servers)?
+            .start_async()
+            .await
+    }
+
+    /// Starts the PCP client on the current tokio runtime, using the server advertised by DHCP
+    /// or the default gateway as the PCP server (only on Linux, see the `discovery` module)
+    pub async fn discover() -> io::Result<Self> {
+        ClientBuilder::<Ipv6Addr>::discover()?.start_async().await
+    }
+}
+
+/// Receives a datagram from the socket, if there is none it never completes
+async fn recv_from(socket: Option<&UdpSocket>, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    match socket {
+        Some(socket) => socket.recv_from(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Converts a std socket into a tokio one
+fn into_tokio(socket: std::net::UdpSocket) -> io::Result<UdpSocket> {
+    socket.set_nonblocking(true)?;
+    UdpSocket::from_std(socket)
+}
+
+/// An asynchronous handle to a requested mapping.
+///
+/// The alerts of the mapping can be received by using it as a `Stream`.
+pub struct AsyncMapHandle<Ip: IpAddress> {
+    state: Arc<AtomicState>,
+    id: MappingId,
+    /// Channel used to send instructions to the client task
+    to_client: mpsc::UnboundedSender<Command<Ip>>,
+    /// Channel used to receive alerts from the client task
+    from_client: mpsc::UnboundedReceiver<Alert>,
+}
+
+impl<Ip: IpAddress> AsyncMapHandle<Ip> {
+    /// Returns the id of the mapping
+    pub fn id(&self) -> MappingId {
+        self.id
+    }
+
+    /// Returns the state of the mapping
+    pub fn state(&self) -> State {
+        self.state.get()
+    }
+
+    /// Requests to renew the mapping for the specified lifetime
+    pub fn renew(&self, lifetime: u32) {
+        self.to_client.send(Command::Renew(self.id, lifetime)).ok();
+    }
+
+    /// Requests to revoke the mapping, which is `Deleting` until the server confirms it
+    /// (`Revoked`) or refuses it (`Error`)
+    pub fn revoke(&self) {
+        self.to_client.send(Command::Revoke(self.id)).ok();
+    }
+
+    /// Waits for an alert to arrive, `None` is returned once the client has stopped
+    pub fn wait_alert(&mut self) -> impl Future<Output = Option<Alert>> + '_ {
+        self.from_client.recv()
+    }
+
+    /// Returns the first alert received if there is one
+    pub fn poll_alert(&mut self) -> Option<Alert> {
+        self.from_client.try_recv().ok()
+    }
+}
+
+impl<Ip: IpAddress> Stream for AsyncMapHandle<Ip> {
+    type Item = Alert;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Alert>> {
+        self.from_client.poll_recv(cx)
+    }
+}
+
+impl<Ip: IpAddress> Drop for AsyncMapHandle<Ip> {
+    fn drop(&mut self) {
+        self.to_client.send(Command::Drop(self.id)).ok();
+    }
+}
@@ -0,0 +1,584 @@
+//! The `ClientBuilder` configures a `Client` (or an `AsyncClient`) before starting
+//! it, the `start` methods of the clients use the default settings.
+//!
+//! Other than the timing of the requests (see the `retry` module) the builder
+//! configures the sockets used by the client: the one used to talk with the PCP
+//! server, which by default is bound to a random port, and the one that listens for
+//! the announcements of the server on the _all hosts_ multicast group, which can
+//! also be disabled. The latter one is bound with `SO_REUSEADDR`, so that more
+//! clients on the same host can receive the announcements.
+//!
+//! The client can be given more candidate servers (see `with_servers`), which are
+//! tried in order until one of them responds. In that case the address of the
+//! client is the one the system uses to reach each server (as described by RFC
+//! 7488), and if they aren't all the same the sockets are bound to the unspecified
+//! address so that the system picks the right one for each datagram.
+//!
+//! The mappings can also be saved in a file (see `state_file` and the `store`
+//! module), which is loaded when the client is started so that the mappings of
+//! the previous run can be restored.
+//!
+//! On Linux, with the `netlink` feature, the client can also follow the changes
+//! of the network (see `watch_network` and the `netlink` module): when the address
+//! used to reach the server changes, the sockets are bound again (if they were
+//! bound to the old address) and the mappings are requested from the new one.
+
+#[cfg(feature = "tokio")]
+use super::async_client::AsyncClient;
+use super::channel;
+use super::client::{Client, Rebind};
+use super::discovery;
+use super::event::Event;
+use super::handle::{Error, Handle};
+use super::retry::{RetryPolicy, RfcPolicy};
+use super::session::PcpSession;
+use super::store::{Persistence, StateStore};
+use super::IpAddress;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc};
+use std::thread::JoinHandle;
+
+/// Port on which the PCP server listens
+const SERVER_PORT: u16 = 5351;
+/// Port on which the PCP server sends the announcements
+const ANNOUNCE_PORT: u16 = 5350;
+
+/// The sockets of a client (see `SocketOptions::bind`), the addresses of the servers and the
+/// function that binds the sockets again
+type Sockets<Ip> = (
+    UdpSocket,
+    Option<UdpSocket>,
+    Vec<SocketAddr>,
+    Option<Rebind<Ip>>,
+);
+
+/// A builder of PCP clients
+///
+/// # Examples
+///
+/// Start a client that gives up on a request after sending it 5 times, and talks to
+/// a server listening on an unprivileged port:
+/**
+
+    let policy = RfcPolicy {
+        mrc: 5,
+        ..RfcPolicy::default()
+    };
+    let handle = ClientBuilder::new(client, server)
+        .retry_policy(policy)
+        .server_port(15351)
+        .announce(false)
+        .start()
+        .unwrap();
+
+*/
+pub struct ClientBuilder<Ip: IpAddress> {
+    /// Addresses of the candidate PCP servers, in order of preference, each with the address
+    /// of the client used to reach it
+    servers: Vec<(Ip, Ip)>,
+    /// Timing of the retransmissions and of the renewals
+    policy: Box<dyn RetryPolicy>,
+    /// Settings of the sockets
+    options: SocketOptions,
+    /// Whether the changes of the network are followed
+    watch: bool,
+    /// Where the mappings are saved, if they are
+    store: Option<StateStore>,
+}
+
+/// The settings of the sockets of a client, which are kept by the client to bind them again
+/// when the network changes
+#[derive(Clone)]
+struct SocketOptions {
+    /// Port of the PCP server
+    server_port: u16,
+    /// Port of the client, 0 means that it's chosen by the system
+    client_port: u16,
+    /// Port on which the announcements are received, if they are listened
+    announce_port: Option<u16>,
+    /// Name of the network interface the sockets are bound to
+    device: Option<String>,
+    /// Scope id (index of the interface) of the IPv6 addresses
+    scope_id: u32,
+    /// TTL (or hop limit) of the packets sent
+    ttl: Option<u32>,
+}
+
+impl<Ip: IpAddress> ClientBuilder<Ip> {
+    /// Creates a builder for a client with the specified address that talks to the specified
+    /// server, with the default settings
+    pub fn new(client: Ip, server: Ip) -> Self {
+        Self {
+            servers: vec![(client, server)],
+            policy: Box::new(RfcPolicy::default()),
+            options: SocketOptions {
+                server_port: SERVER_PORT,
+                client_port: 0,
+                announce_port: Some(ANNOUNCE_PORT),
+                device: None,
+                scope_id: 0,
+                ttl: None,
+            },
+            watch: false,
+            store: None,
+        }
+    }
+
+    /// Sets the policy used to retransmit the requests and to renew the mappings, by default
+    /// it's the one of the RFC (see `RfcPolicy`)
+    pub fn retry_policy<P: RetryPolicy + 'static>(mut self, policy: P) -> Self {
+        self.policy = Box::new(policy);
+        self
+    }
+
+    /// Sets the port of the PCP server, by default it's 5351
+    pub fn server_port(mut self, port: u16) -> Self {
+        self.options.server_port = port;
+        self
+    }
+
+    /// Sets the port used by the client to send the requests, by default it's chosen by the
+    /// system
+    pub fn client_port(mut self, port: u16) -> Self {
+        self.options.client_port = port;
+        self
+    }
+
+    /// Sets the port on which the announcements of the server are received, by default it's
+    /// 5350
+    pub fn announce_port(mut self, port: u16) -> Self {
+        self.options.announce_port = Some(port);
+        self
+    }
+
+    /// Enables or disables the reception of the announcements of the server, by default it's
+    /// enabled. Without it the client can still notice a reboot of the server from the
+    /// responses it receives
+    pub fn announce(mut self, enable: bool) -> Self {
+        self.options.announce_port = match enable {
+            true => self.options.announce_port.or(Some(ANNOUNCE_PORT)),
+            false => None,
+        };
+        self
+    }
+
+    /// Binds the sockets to the network interface with the specified name (`SO_BINDTODEVICE`)
+    #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+    pub fn bind_device(mut self, interface: &str) -> Self {
+        self.options.device = Some(interface.to_owned());
+        self
+    }
+
+    /// Sets the TTL (for IPv4) or the hop limit (for IPv6) of the packets sent to the server
+    pub fn ttl(mut self, ttl: u32) -> Self {
+        self.options.ttl = Some(ttl);
+        self
+    }
+
+    /// Follows the changes of the addresses and of the routes of the system, so that the
+    /// client keeps talking to the server when the address used to reach it changes (see the
+    /// `netlink` module). By default it's disabled
+    #[cfg(all(feature = "netlink", target_os = "linux"))]
+    pub fn watch_network(mut self, enable: bool) -> Self {
+        self.watch = enable;
+        self
+    }
+
+    /// Saves the mappings in the file at the specified path whenever they change, so that they
+    /// can be requested again after a restart (see `Handle::restore`). The file is loaded when
+    /// the client is started, which fails if it's not valid
+    pub fn state_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.store = Some(StateStore::new(path));
+        self
+    }
+
+    /// Loads the mappings of the previous run, if they are saved
+    fn persistence(&self) -> io::Result<Option<Persistence<Ip>>> {
+        self.store.clone().map(Persistence::open).transpose()
+    }
+
+    /// Returns the address the sockets are bound to (see `bind_address`)
+    fn client(&self) -> Ip {
+        bind_address(self.servers.iter().map(|&(client, _)| client))
+    }
+
+    /// Creates the session driven by the client
+    fn session(self) -> PcpSession<Ip> {
+        let mut servers = self.servers.into_iter();
+        let (client, _) = servers.next().expect("there is always a server");
+        let mut session = PcpSession::with_policy(client, self.policy);
+        for (client, _) in servers {
+            session.add_server(client);
+        }
+        session
+    }
+}
+
+impl SocketOptions {
+    /// Creates an UDP socket bound to the specified address, configured as requested
+    fn socket(&self, addr: SocketAddr, reuse_address: bool) -> io::Result<Socket> {
+        let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+        socket.set_reuse_address(reuse_address)?;
+        if addr.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        #[cfg(any(target_os = "android", target_os = "fuchsia", target_os = "linux"))]
+        if let Some(device) = &self.device {
+            socket.bind_device(Some(device.as_bytes()))?;
+        }
+        socket.bind(&addr.into())?;
+        Ok(socket)
+    }
+
+    /// Creates the socket used to talk with the PCP servers, bound to the specified address, and
+    /// the one listening for announcements (if enabled). The latter is bound to the unspecified
+    /// address, as the datagrams sent to a multicast group aren't received by the sockets bound
+    /// to an unicast address, the interface is chosen when joining the group
+    fn bind(&self, client: SocketAddr) -> io::Result<(Socket, Option<Socket>)> {
+        let socket = self.socket(client, false)?;
+        let announce = match self.announce_port {
+            Some(port) => {
+                let unspecified = match client {
+                    SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+                };
+                Some(self.socket(SocketAddr::new(unspecified, port), true)?)
+            }
+            None => None,
+        };
+        Ok((socket, announce))
+    }
+
+    /// Creates the IPv4 sockets of a client with the specified address
+    fn v4(&self, client: Ipv4Addr) -> io::Result<(UdpSocket, Option<UdpSocket>)> {
+        let (socket, announce) = self.bind(SocketAddrV4::new(client, self.client_port).into())?;
+        if let Some(ttl) = self.ttl {
+            socket.set_ttl(ttl)?;
+        }
+        if let Some(announce) = &announce {
+            announce.join_multicast_v4(&Ipv4Addr::new(224, 0, 0, 1), &client)?;
+        }
+        Ok((socket.into(), announce.map(Into::into)))
+    }
+
+    /// Creates the IPv6 sockets of a client with the specified address
+    fn v6(&self, client: Ipv6Addr) -> io::Result<(UdpSocket, Option<UdpSocket>)> {
+        let client = SocketAddrV6::new(client, self.client_port, 0, self.scope_id);
+        let (socket, announce) = self.bind(client.into())?;
+        if let Some(hops) = self.ttl {
+            socket.set_unicast_hops_v6(hops)?;
+        }
+        if let Some(announce) = &announce {
+            let all_hosts = Ipv6Addr::new(0xff02, 0, 0, 0, 0, 0, 0, 1);
+            announce.join_multicast_v6(&all_hosts, self.scope_id)?;
+        }
+        Ok((socket.into(), announce.map(Into::into)))
+    }
+}
+
+impl ClientBuilder<Ipv4Addr> {
+    /// Creates a builder for a client that talks to the first server of the list (which is in
+    /// order of preference) that responds, from the address the system uses to reach it. The
+    /// servers that can't be reached are left out, it fails if none of them can be
+    pub fn with_servers(servers: &[Ipv4Addr]) -> io::Result<Self> {
+        let servers = candidates(servers, |&server| {
+            discovery::source_v4(server).map(|client| (client, server))
+        })?;
+        Ok(Self {
+            servers,
+            ..Self::new(Ipv4Addr::UNSPECIFIED, Ipv4Addr::UNSPECIFIED)
+        })
+    }
+
+    /// Creates a builder for a client that talks to the server advertised by DHCP or, if there
+    /// isn't any, to the default gateway (see the `discovery` module)
+    pub fn discover() -> io::Result<Self> {
+        Self::with_servers(&discovery::servers_v4()?)
+    }
+
+    /// Creates the sockets of the client, and returns them with the addresses of the servers
+    /// and the function that binds them again (if the network is watched)
+    fn sockets(&self) -> io::Result<Sockets<Ipv4Addr>> {
+        let (socket, announce) = self.options.v4(self.client())?;
+        let port = self.options.server_port;
+        let servers = self
+            .servers
+            .iter()
+            .map(|&(_, server)| SocketAddrV4::new(server, port).into())
+            .collect();
+        let options = self.options.clone();
+        let rebind = match self.watch {
+            true => Some(Box::new(move |client| options.v4(client)) as Rebind<_>),
+            false => None,
+        };
+        Ok((socket, announce, servers, rebind))
+    }
+
+    /// Starts the PCP client and returns it's `Handle` which is used to request mappings
+    pub fn start(self) -> io::Result<Handle<Ipv4Addr>> {
+        let (to_handle, from_client) = channel::channel();
+        let (tx, thread) = self.launch(to_handle)?;
+        Ok(Handle::new(tx, thread, Arc::new(from_client)))
+    }
+
+    /// Starts the PCP client, which sends its errors through `to_handle`, and returns the
+    /// `Sender` of its events and its main thread
+    pub(crate) fn launch(
+        self,
+        to_handle: channel::Sender<Error>,
+    ) -> io::Result<(mpsc::Sender<Event<Ipv4Addr>>, JoinHandle<()>)> {
+        let (socket, announce, servers, rebind) = self.sockets()?;
+        let persistence = self.persistence()?;
+        let session = self.session();
+        Client::launch(
+            socket,
+            announce,
+            servers,
+            rebind,
+            session,
+            persistence,
+            to_handle,
+        )
+    }
+
+    /// Starts the asynchronous PCP client on the current tokio runtime
+    #[cfg(feature = "tokio")]
+    pub async fn start_async(self) -> io::Result<AsyncClient<Ipv4Addr>> {
+        let (socket, announce, servers, rebind) = self.sockets()?;
+        let persistence = self.persistence()?;
+        let session = self.session();
+        AsyncClient::launch(socket, announce, servers, rebind, session, persistence)
+    }
+}
+
+impl ClientBuilder<Ipv6Addr> {
+    /// Creates a builder for a client that talks to the first server of the list (which is in
+    /// order of preference) that responds, from the address the system uses to reach it. The
+    /// servers that can't be reached are left out, it fails if none of them can be
+    pub fn with_servers(servers: &[Ipv6Addr]) -> io::Result<Self> {
+        let servers: Vec<_> = servers.iter().map(|&server| (server, 0)).collect();
+        Self::with_scoped_servers(&servers)
+    }
+
+    /// Like `with_servers`, but each server is paired with the index of its interface. The
+    /// sockets are bound to a single interface, so only the servers on the same interface of
+    /// the first reachable one are kept
+    fn with_scoped_servers(servers: &[(Ipv6Addr, u32)]) -> io::Result<Self> {
+        let servers = candidates(servers, |&(server, scope_id)| {
+            discovery::source_v6(server, scope_id).map(|client| (client, server, scope_id))
+        })?;
+        let scope_id = servers[0].2;
+        let servers = servers
+            .into_iter()
+            .filter(|&(_, _, other)| other == scope_id)
+            .map(|(client, server, _)| (client, server))
+            .collect();
+        Ok(Self {
+            servers,
+            ..Self::new(Ipv6Addr::UNSPECIFIED, Ipv6Addr::UNSPECIFIED)
+        }
+        .scope_id(scope_id))
+    }
+
+    /// Creates a builder for a client that talks to the server advertised by DHCP or, if there
+    /// isn't any, to the default gateway (see the `discovery` module)
+    pub fn discover() -> io::Result<Self> {
+        Self::with_scoped_servers(&discovery::servers_v6()?)
+    }
+
+    /// Sets the scope id (the index of the network interface) of the addresses of the client
+    /// and of the server, needed when they are link-local addresses. It's also the interface
+    /// on which the announcements are received
+    pub fn scope_id(mut self, scope_id: u32) -> Self {
+        self.options.scope_id = scope_id;
+        self
+    }
+
+    /// Creates the sockets of the client, and returns them with the addresses of the servers
+    /// and the function that binds them again (if the network is watched)
+    fn sockets(&self) -> io::Result<Sockets<Ipv6Addr>> {
+        let (socket, announce) = self.options.v6(self.client())?;
+        let (port, scope_id) = (self.options.server_port, self.options.scope_id);
+        let servers = self
+            .servers
+            .iter()
+            .map(|&(_, server)| SocketAddrV6::new(server, port, 0, scope_id).into())
+            .collect();
+        let options = self.options.clone();
+        let rebind = match self.watch {
+            true => Some(Box::new(move |client| options.v6(client)) as Rebind<_>),
+            false => None,
+        };
+        Ok((socket, announce, servers, rebind))
+    }
+
+    /// Starts the PCP client and returns it's `Handle` which is used to request mappings
+    pub fn start(self) -> io::Result<Handle<Ipv6Addr>> {
+        let (to_handle, from_client) = channel::channel();
+        let (tx, thread) = self.launch(to_handle)?;
+        Ok(Handle::new(tx, thread, Arc::new(from_client)))
+    }
+
+    /// Starts the PCP client, which sends its errors through `to_handle`, and returns the
+    /// `Sender` of its events and its main thread
+    pub(crate) fn launch(
+        self,
+        to_handle: channel::Sender<Error>,
+    ) -> io::Result<(mpsc::Sender<Event<Ipv6Addr>>, JoinHandle<()>)> {
+        let (socket, announce, servers, rebind) = self.sockets()?;
+        let persistence = self.persistence()?;
+        let session = self.session();
+        Client::launch(
+            socket,
+            announce,
+            servers,
+            rebind,
+            session,
+            persistence,
+            to_handle,
+        )
+    }
+
+    /// Starts the asynchronous PCP client on the current tokio runtime
+    #[cfg(feature = "tokio")]
+    pub async fn start_async(self) -> io::Result<AsyncClient<Ipv6Addr>> {
+        let (socket, announce, servers, rebind) = self.sockets()?;
+        let persistence = self.persistence()?;
+        let session = self.session();
+        AsyncClient::launch(socket, announce, servers, rebind, session, persistence)
+    }
+}
+
+/// Maps each of the servers to its candidate with the specified function, leaving out the ones
+/// for which it fails. If none is left the last error is returned
+fn candidates<S, C>(servers: &[S], source: impl Fn(&S) -> io::Result<C>) -> io::Result<Vec<C>> {
+    let mut error = None;
+    let candidates: Vec<_> = servers
+        .iter()
+        .filter_map(|server| source(server).map_err(|err| error = Some(err)).ok())
+        .collect();
+    match (candidates.is_empty(), error) {
+        (false, _) => Ok(candidates),
+        (true, Some(err)) => Err(err),
+        (true, None) => Err(io::Error::new(io::ErrorKind::NotFound, "no PCP server")),
+    }
+}
+
+/// Returns the address the sockets of a client are bound to, given the addresses used to reach
+/// each server: if they are all the same it's that one, otherwise it's the unspecified one, so
+/// that the system picks the right one for each server
+pub(crate) fn bind_address<Ip: IpAddress>(mut clients: impl Iterator<Item = Ip>) -> Ip {
+    let first = match clients.next() {
+        Some(first) => first,
+        None => return Ip::UNSPECIFIED,
+    };
+    let same = |other: Ip| -> bool { Into::<IpAddr>::into(other) == first.into() };
+    match clients.all(same) {
+        true => first,
+        false => Ip::UNSPECIFIED,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const LOCALHOST: Ipv4Addr = Ipv4Addr::LOCALHOST;
+
+    /// Returns the socket settings of a client that receives the announcements on the port
+    fn options(announce_port: u16) -> SocketOptions {
+        ClientBuilder::new(LOCALHOST, LOCALHOST)
+            .announce_port(announce_port)
+            .options
+    }
+
+    #[test]
+    fn announce_socket_bound_to_unspecified() {
+        let (socket, announce) = options(15360).v4(LOCALHOST).unwrap();
+        assert_eq!(socket.local_addr().unwrap().ip(), LOCALHOST);
+        let announce = announce.unwrap().local_addr().unwrap();
+        assert_eq!(
+            announce,
+            SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 15360).into()
+        );
+
+        let (_, announce) = ClientBuilder::new(LOCALHOST, LOCALHOST)
+            .announce(false)
+            .options
+            .v4(LOCALHOST)
+            .unwrap();
+        assert!(announce.is_none());
+    }
+
+    #[test]
+    fn announce_received_by_every_client() {
+        let port = 15361;
+        let clients: Vec<_> = (0..2)
+            .map(|_| options(port).v4(LOCALHOST).unwrap())
+            .collect();
+
+        let sender = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        sender.set_multicast_if_v4(&LOCALHOST).unwrap();
+        sender.set_multicast_loop_v4(true).unwrap();
+        let group = SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 1), port);
+        sender.send_to(b"announce", &group.into()).unwrap();
+
+        for (_, announce) in &clients {
+            let announce = announce.as_ref().unwrap();
+            announce
+                .set_read_timeout(Some(Duration::from_secs(1)))
+                .unwrap();
+            let mut buf = [0; 16];
+            let (len, _) = announce.recv_from(&mut buf).unwrap();
+            assert_eq!(&buf[..len], b"announce");
+        }
+    }
+
+    #[test]
+    fn custom_retry_policy() {
+        use crate::types::ProtocolNumber;
+        use crate::{InboundMap, RequestType, State};
+        use std::time::Instant;
+
+        let irt = Duration::from_millis(100);
+        let mut session = ClientBuilder::new(LOCALHOST, LOCALHOST)
+            .retry_policy(RfcPolicy {
+                irt,
+                mrc: 3,
+                ..RfcPolicy::default()
+            })
+            .session();
+        let start = Instant::now();
+        let map = InboundMap::new(6000, 120).protocol(ProtocolNumber::Tcp);
+        let id = session.request_inbound(map, RequestType::Once, start);
+
+        // Sent three times, at about 0, 100 and 300 milliseconds, then given up when the last
+        // one isn't answered either (each time can be 10% longer than the double of the last)
+        let mut sent = 0;
+        while let Some(timeout) = session.poll_timeout() {
+            sent += std::iter::from_fn(|| session.poll_transmit()).count();
+            assert!(timeout <= start + irt * 9);
+            session.handle_timeout(timeout);
+        }
+        assert_eq!(sent, 3);
+        assert!(session.poll_transmit().is_none());
+        assert_eq!(session.state(id), Some(State::Expired));
+    }
+
+    #[test]
+    fn bind_address_of_the_clients() {
+        let other = Ipv4Addr::new(192, 0, 2, 2);
+        assert_eq!(
+            bind_address([LOCALHOST, LOCALHOST].iter().copied()),
+            LOCALHOST
+        );
+        assert_eq!(
+            bind_address([LOCALHOST, other].iter().copied()),
+            Ipv4Addr::UNSPECIFIED
+        );
+    }
+}
@@ -0,0 +1,141 @@
+//! A multi-producer single-consumer channel that can be consumed both by blocking
+//! threads and by futures.
+//!
+//! It mirrors the API of `std::sync::mpsc` (which is what the handles were using
+//! before) but the `Receiver` can also be polled: the waker of the last task that
+//! polled it is woken as soon as a value is sent or when all the senders are gone,
+//! so the handles don't depend on any specific async runtime.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::mpsc::{RecvError, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// The state shared between the two ends of the channel
+struct Shared<T> {
+    /// Values sent but not yet received
+    queue: VecDeque<T>,
+    /// Waker of the last task that polled the receiver
+    waker: Option<Waker>,
+    /// Number of senders alive
+    senders: usize,
+    /// Tells if the receiver is still alive
+    receiver: bool,
+}
+
+type Inner<T> = Arc<(Mutex<Shared<T>>, Condvar)>;
+
+/// Creates a new channel, returning the sender and the receiver halves
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new((
+        Mutex::new(Shared {
+            queue: VecDeque::new(),
+            waker: None,
+            senders: 1,
+            receiver: true,
+        }),
+        Condvar::new(),
+    ));
+    (Sender(Arc::clone(&inner)), Receiver(inner))
+}
+
+/// The sending half of the channel
+pub struct Sender<T>(Inner<T>);
+
+impl<T> Sender<T> {
+    /// Sends a value through the channel, if the receiver has been dropped the value is
+    /// returned back
+    pub fn send(&self, value: T) -> Result<(), T> {
+        let (lock, cvar) = &*self.0;
+        let mut shared = lock.lock().unwrap();
+        if !shared.receiver {
+            return Err(value);
+        }
+        shared.queue.push_back(value);
+        if let Some(waker) = shared.waker.take() {
+            waker.wake();
+        }
+        cvar.notify_one();
+        Ok(())
+    }
+}
+
+impl<T> fmt::Debug for Sender<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Sender { .. }")
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.0 .0.lock().unwrap().senders += 1;
+        Self(Arc::clone(&self.0))
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let (lock, cvar) = &*self.0;
+        let mut shared = lock.lock().unwrap();
+        shared.senders -= 1;
+        if shared.senders == 0 {
+            // Wake the receiver so that it can see that the channel is closed
+            if let Some(waker) = shared.waker.take() {
+                waker.wake();
+            }
+            cvar.notify_all();
+        }
+    }
+}
+
+/// The receiving half of the channel
+pub struct Receiver<T>(Inner<T>);
+
+impl<T> Receiver<T> {
+    /// Blocks until a value is received, fails if all the senders have been dropped
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let (lock, cvar) = &*self.0;
+        let mut shared = lock.lock().unwrap();
+        loop {
+            if let Some(value) = shared.queue.pop_front() {
+                return Ok(value);
+            }
+            if shared.senders == 0 {
+                return Err(RecvError);
+            }
+            shared = cvar.wait(shared).unwrap();
+        }
+    }
+
+    /// Returns a value if there is one, without blocking
+    pub fn try_recv(&self) -> Result<T, TryRecvError> {
+        let mut shared = self.0 .0.lock().unwrap();
+        match shared.queue.pop_front() {
+            Some(value) => Ok(value),
+            None if shared.senders == 0 => Err(TryRecvError::Disconnected),
+            None => Err(TryRecvError::Empty),
+        }
+    }
+
+    /// Polls for a value, `Ready(None)` is returned once all the senders have been dropped
+    pub fn poll_recv(&self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let mut shared = self.0 .0.lock().unwrap();
+        match shared.queue.pop_front() {
+            Some(value) => Poll::Ready(Some(value)),
+            None if shared.senders == 0 => Poll::Ready(None),
+            None => {
+                shared.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let mut shared = self.0 .0.lock().unwrap();
+        shared.receiver = false;
+        shared.queue.clear();
+    }
+}
@@ -0,0 +1,389 @@
+//! The PCP servers advertised by DHCP, read from the lease files of the DHCP
+//! clients.
+//!
+//! RFC 7291 defines the option 158 of DHCPv4 and the option 86 of DHCPv6 to tell
+//! the hosts which PCP servers to use:
+//! - the DHCPv4 option contains one or more lists of IPv4 addresses, each one
+//!   preceded by its length in bytes, and each list is a different server;
+//! - the DHCPv6 option contains the IPv6 addresses of a server (the IPv4-mapped
+//!   ones refer to IPv4 servers), and it's repeated for each server.
+//!
+//! In both cases the servers are in order of preference. The leases are stored in
+//! different formats by the different clients:
+//! - `dhclient` writes `lease { ... }` (and `lease6 { ... }`) blocks of text, the
+//!   unknown options are written as `option unknown-158 ...;` with their bytes in
+//!   hexadecimal separated by colons (or as a quoted string), the last lease is the
+//!   most recent one;
+//! - `dhcpcd` saves the DHCP messages received, as they are.
+//!
+//! `systemd-networkd` isn't supported: its leases only keep the private options
+//! (224 to 254), so the PCP servers are never written.
+
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// Code of the DHCPv4 PCP server option
+pub const OPTION_V4_PCP_SERVER: u8 = 158;
+/// Code of the DHCPv6 PCP server option
+pub const OPTION_V6_PCP_SERVER: u16 = 86;
+
+/// Size of the fixed part of a DHCPv4 message (the BOOTP header)
+const BOOTP_SIZE: usize = 236;
+/// The magic cookie that precedes the DHCPv4 options
+const MAGIC_COOKIE: [u8; 4] = [99, 130, 83, 99];
+/// Size of the header of a DHCPv6 message (message type and transaction id)
+const DHCPV6_HEADER_SIZE: usize = 4;
+
+/// Parses the data of the DHCPv4 PCP server option, returning the addresses of each server, with
+/// the servers in order of preference. If the option is malformed no server is returned
+pub fn parse_v4_option(mut data: &[u8]) -> Vec<Vec<Ipv4Addr>> {
+    let mut servers = Vec::new();
+    while let Some((&len, rest)) = data.split_first() {
+        let len = len as usize;
+        if len == 0 || !len.is_multiple_of(4) || rest.len() < len {
+            return Vec::new();
+        }
+        let (list, rest) = rest.split_at(len);
+        servers.push(
+            list.chunks(4)
+                .map(|a| Ipv4Addr::new(a[0], a[1], a[2], a[3]))
+                .collect(),
+        );
+        data = rest;
+    }
+    servers
+}
+
+/// Parses the data of a DHCPv6 PCP server option, returning the addresses of the server. If the
+/// option is malformed no address is returned
+pub fn parse_v6_option(data: &[u8]) -> Vec<Ipv6Addr> {
+    if !data.len().is_multiple_of(16) {
+        return Vec::new();
+    }
+    data.chunks(16)
+        .map(|a| {
+            let mut octets = [0; 16];
+            octets.copy_from_slice(a);
+            Ipv6Addr::from(octets)
+        })
+        .collect()
+}
+
+/// Parses a value written by `dhclient`: either bytes in hexadecimal separated by colons or a
+/// quoted string, with octal escapes
+fn parse_dhclient_value(value: &str) -> Option<Vec<u8>> {
+    let value = value.trim();
+    match value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) {
+        Some(string) => {
+            let mut bytes = Vec::new();
+            let mut chars = string.bytes();
+            while let Some(c) = chars.next() {
+                if c != b'\\' {
+                    bytes.push(c);
+                    continue;
+                }
+                // Either an escaped character or three octal digits
+                let next = chars.next()?;
+                if !next.is_ascii_digit() {
+                    bytes.push(next);
+                    continue;
+                }
+                let digits = [next, chars.next()?, chars.next()?];
+                let digits = std::str::from_utf8(&digits).ok()?;
+                bytes.push(u8::from_str_radix(digits, 8).ok()?);
+            }
+            Some(bytes)
+        }
+        None => value
+            .split(':')
+            .map(|b| u8::from_str_radix(b, 16).ok())
+            .collect(),
+    }
+}
+
+/// Parses the lease file of `dhclient`, returning the addresses of the PCP servers of the most
+/// recent DHCPv4 and DHCPv6 leases (in this order)
+pub fn parse_dhclient(leases: &str) -> (Vec<Vec<Ipv4Addr>>, Vec<Vec<Ipv6Addr>>) {
+    let (mut v4, mut v6) = (Vec::new(), Vec::new());
+    // Whether the current block is a DHCPv6 lease
+    let mut lease6 = false;
+    for line in leases.lines().map(str::trim) {
+        if line.starts_with("lease6") {
+            lease6 = true;
+            v6.clear();
+        } else if line.starts_with("lease") {
+            lease6 = false;
+            v4.clear();
+        }
+        let option = match line.strip_prefix("option ") {
+            Some(option) => option.trim_end_matches(';'),
+            None => continue,
+        };
+        let (name, value) = match option.split_once(char::is_whitespace) {
+            Some(option) => option,
+            None => continue,
+        };
+        let data = match parse_dhclient_value(value) {
+            Some(data) => data,
+            None => continue,
+        };
+        // The DHCPv6 options are prefixed with `dhcp6.`
+        let name = name.rsplit('.').next().unwrap_or(name);
+        match (lease6, name) {
+            (false, "unknown-158") => v4 = parse_v4_option(&data),
+            (true, "unknown-86") => {
+                let server = parse_v6_option(&data);
+                if !server.is_empty() {
+                    v6.push(server);
+                }
+            }
+            _ => (),
+        }
+    }
+    (v4, v6)
+}
+
+/// Parses a DHCPv4 message saved by `dhcpcd`, returning the addresses of the PCP servers it
+/// contains
+pub fn parse_dhcpcd(message: &[u8]) -> Vec<Vec<Ipv4Addr>> {
+    let options = match message.get(BOOTP_SIZE..) {
+        Some(options) if options.starts_with(&MAGIC_COOKIE) => &options[MAGIC_COOKIE.len()..],
+        _ => return Vec::new(),
+    };
+    // An option can be split in more parts, which have to be concatenated (RFC 3396)
+    let mut data = Vec::new();
+    let mut i = 0;
+    while let Some(&code) = options.get(i) {
+        match code {
+            // Pad
+            0 => i += 1,
+            // End
+            255 => break,
+            _ => {
+                let len = match options.get(i + 1) {
+                    Some(&len) => len as usize,
+                    None => break,
+                };
+                let value = match options.get(i + 2..i + 2 + len) {
+                    Some(value) => value,
+                    None => break,
+                };
+                if code == OPTION_V4_PCP_SERVER {
+                    data.extend_from_slice(value);
+                }
+                i += 2 + len;
+            }
+        }
+    }
+    parse_v4_option(&data)
+}
+
+/// Parses a DHCPv6 message saved by `dhcpcd`, returning the addresses of the PCP servers it
+/// contains
+pub fn parse_dhcpcd6(message: &[u8]) -> Vec<Vec<Ipv6Addr>> {
+    let mut options = message.get(DHCPV6_HEADER_SIZE..).unwrap_or_default();
+    let mut servers = Vec::new();
+    while options.len() >= 4 {
+        let code = u16::from_be_bytes([options[0], options[1]]);
+        let len = u16::from_be_bytes([options[2], options[3]]) as usize;
+        let value = match options.get(4..4 + len) {
+            Some(value) => value,
+            None => break,
+        };
+        if code == OPTION_V6_PCP_SERVER {
+            let server = parse_v6_option(value);
+            if !server.is_empty() {
+                servers.push(server);
+            }
+        }
+        options = &options[4 + len..];
+    }
+    servers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DHCLIENT_LEASES: &str = r#"lease {
+  interface "eth0";
+  fixed-address 192.168.1.10;
+  option subnet-mask 255.255.255.0;
+  option routers 192.168.1.1;
+  option unknown-158 4:c0:a8:1:fe;
+  renew 2 2023/11/14 22:13:20;
+  expire 3 2023/11/15 10:13:20;
+}
+lease {
+  interface "eth0";
+  fixed-address 192.168.1.10;
+  option subnet-mask 255.255.255.0;
+  option routers 192.168.1.1;
+  option unknown-158 8:c0:a8:1:1:c0:a8:1:2:4:a:0:0:1;
+  renew 3 2023/11/15 04:13:20;
+  expire 3 2023/11/15 16:13:20;
+}
+"#;
+
+    const DHCLIENT6_LEASES: &str = r#"default-duid "\000\001\000\001\054\315\120\200\122\124\000\022\064\126";
+lease6 {
+  interface "eth0";
+  ia-na 1a:2b:3c:4d {
+    starts 1700000000;
+    renew 3600;
+    rebind 5400;
+    iaaddr 2001:db8::100 {
+      starts 1700000000;
+      preferred-life 7200;
+      max-life 10800;
+    }
+  }
+  option dhcp6.client-id 0:1:0:1:2c:cd:50:80:52:54:0:12:34:56;
+  option dhcp6.unknown-86 20:1:d:b8:0:0:0:0:0:0:0:0:0:0:0:1;
+  option dhcp6.unknown-86 0:0:0:0:0:0:0:0:0:0:ff:ff:c0:0:2:1:20:1:d:b8:0:0:0:0:0:0:0:0:0:0:0:2;
+}
+"#;
+
+    /// Builds a DHCPv4 message with the specified options
+    fn dhcp_message(options: &[(u8, &[u8])]) -> Vec<u8> {
+        let mut message = vec![0; BOOTP_SIZE];
+        message.extend_from_slice(&MAGIC_COOKIE);
+        for (code, value) in options {
+            message.extend_from_slice(&[*code, value.len() as u8]);
+            message.extend_from_slice(value);
+        }
+        message.push(255);
+        message
+    }
+
+    /// Builds a DHCPv6 reply with the specified options
+    fn dhcp6_message(options: &[(u16, &[u8])]) -> Vec<u8> {
+        let mut message = vec![7, 0x12, 0x34, 0x56];
+        for (code, value) in options {
+            message.extend_from_slice(&code.to_be_bytes());
+            message.extend_from_slice(&(value.len() as u16).to_be_bytes());
+            message.extend_from_slice(value);
+        }
+        message
+    }
+
+    #[test]
+    fn v4_option() {
+        let data = [4, 192, 168, 1, 1, 8, 10, 0, 0, 1, 10, 0, 0, 2];
+        assert_eq!(
+            parse_v4_option(&data),
+            [
+                vec![Ipv4Addr::new(192, 168, 1, 1)],
+                vec![Ipv4Addr::new(10, 0, 0, 1), Ipv4Addr::new(10, 0, 0, 2)],
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_v4_option() {
+        // Not a multiple of 4
+        assert!(parse_v4_option(&[4, 192, 168, 1, 1, 3, 10, 0, 0]).is_empty());
+        // Longer than the option
+        assert!(parse_v4_option(&[8, 192, 168, 1, 1]).is_empty());
+        // Empty list
+        assert!(parse_v4_option(&[0]).is_empty());
+    }
+
+    #[test]
+    fn dhclient_most_recent_lease() {
+        let (v4, v6) = parse_dhclient(DHCLIENT_LEASES);
+        assert_eq!(
+            v4,
+            [
+                vec![Ipv4Addr::new(192, 168, 1, 1), Ipv4Addr::new(192, 168, 1, 2)],
+                vec![Ipv4Addr::new(10, 0, 0, 1)],
+            ]
+        );
+        assert!(v6.is_empty());
+    }
+
+    #[test]
+    fn dhclient_quoted_option() {
+        let leases = "lease {\n  option unknown-158 \"\\004\\300\\250\\001\\001\";\n}\n";
+        let (v4, _) = parse_dhclient(leases);
+        assert_eq!(v4, [vec![Ipv4Addr::new(192, 168, 1, 1)]]);
+    }
+
+    #[test]
+    fn dhclient_lease6() {
+        let (v4, v6) = parse_dhclient(DHCLIENT6_LEASES);
+        assert!(v4.is_empty());
+        assert_eq!(
+            v6,
+            [
+                vec!["2001:db8::1".parse::<Ipv6Addr>().unwrap()],
+                vec![
+                    Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped(),
+                    "2001:db8::2".parse().unwrap(),
+                ],
+            ]
+        );
+    }
+
+    #[test]
+    fn dhcpcd_options() {
+        let message = dhcp_message(&[
+            (53, &[5]),
+            (OPTION_V4_PCP_SERVER, &[4, 192, 168, 1, 1]),
+            (3, &[192, 168, 1, 1]),
+        ]);
+        assert_eq!(
+            parse_dhcpcd(&message),
+            [vec![Ipv4Addr::new(192, 168, 1, 1)]]
+        );
+    }
+
+    #[test]
+    fn dhcpcd_concatenated_option() {
+        // The first list is split between the two parts of the option (RFC 3396)
+        let message = dhcp_message(&[
+            (OPTION_V4_PCP_SERVER, &[8, 192, 168, 1, 1]),
+            (3, &[192, 168, 1, 1]),
+            (OPTION_V4_PCP_SERVER, &[192, 168, 1, 2, 4, 10, 0, 0, 1]),
+        ]);
+        assert_eq!(
+            parse_dhcpcd(&message),
+            [
+                vec![Ipv4Addr::new(192, 168, 1, 1), Ipv4Addr::new(192, 168, 1, 2)],
+                vec![Ipv4Addr::new(10, 0, 0, 1)],
+            ]
+        );
+    }
+
+    #[test]
+    fn dhcpcd_not_dhcp() {
+        let mut message = dhcp_message(&[(OPTION_V4_PCP_SERVER, &[4, 192, 168, 1, 1])]);
+        message[BOOTP_SIZE] = 0;
+        assert!(parse_dhcpcd(&message).is_empty());
+        assert!(parse_dhcpcd(&message[..BOOTP_SIZE]).is_empty());
+    }
+
+    #[test]
+    fn dhcpcd6_options() {
+        let server: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let mapped = Ipv4Addr::new(192, 0, 2, 1).to_ipv6_mapped();
+        let second = [mapped.octets(), server.octets()].concat();
+        let message = dhcp6_message(&[
+            (1, &[0, 3, 0, 1]),
+            (OPTION_V6_PCP_SERVER, &server.octets()),
+            (OPTION_V6_PCP_SERVER, &second),
+            // Malformed, ignored
+            (OPTION_V6_PCP_SERVER, &[0; 8]),
+        ]);
+        assert_eq!(
+            parse_dhcpcd6(&message),
+            [vec![server], vec![mapped, server]]
+        );
+    }
+
+    #[test]
+    fn dhcpcd6_truncated() {
+        let server: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let message = dhcp6_message(&[(OPTION_V6_PCP_SERVER, &server.octets())]);
+        assert!(parse_dhcpcd6(&message[..message.len() - 1]).is_empty());
+    }
+}
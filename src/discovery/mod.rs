@@ -0,0 +1,159 @@
+//! The discovery of the PCP server.
+//!
+//! The RFC says that, unless it's configured in some other way, the PCP server is
+//! the default gateway of the host. One of the ways to configure it is DHCP (see
+//! RFC 7291), so the servers advertised by DHCP are preferred: they are read from
+//! the lease files of the DHCP clients (see the `dhcp` module), and only if there
+//! isn't any the default gateway is read from the routing table of the kernel (see
+//! the `route` module). Both of them are available only on Linux.
+//!
+//! The address of the client is then the one that the system would use to reach
+//! the server, which is found by connecting an UDP socket to it (no packet is
+//! actually sent).
+
+pub mod dhcp;
+pub mod route;
+
+use std::fs;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6, UdpSocket};
+use std::path::Path;
+
+/// Directories where `dhclient` stores its leases
+const DHCLIENT_DIRS: &[&str] = &["/var/lib/dhcp", "/var/lib/dhclient"];
+/// Directories where `dhcpcd` stores its leases
+const DHCPCD_DIRS: &[&str] = &["/var/lib/dhcpcd", "/var/db/dhcpcd"];
+
+/// Returns the address that the system uses to reach the specified address
+pub(crate) fn source(addr: SocketAddr) -> io::Result<IpAddr> {
+    let unspecified: SocketAddr = match addr {
+        SocketAddr::V4(_) => SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0).into(),
+    };
+    let socket = UdpSocket::bind(unspecified)?;
+    socket.connect(addr)?;
+    Ok(socket.local_addr()?.ip())
+}
+
+/// Returns the IPv4 address that the system uses to reach the PCP server
+pub fn source_v4(server: Ipv4Addr) -> io::Result<Ipv4Addr> {
+    match source(SocketAddrV4::new(server, 5351).into())? {
+        IpAddr::V4(addr) => Ok(addr),
+        IpAddr::V6(_) => unreachable!("an IPv4 socket has an IPv4 address"),
+    }
+}
+
+/// Returns the IPv6 address that the system uses to reach the PCP server, which is on the
+/// interface with the specified index (only relevant for link-local addresses)
+pub fn source_v6(server: Ipv6Addr, scope_id: u32) -> io::Result<Ipv6Addr> {
+    match source(SocketAddrV6::new(server, 5351, 0, scope_id).into())? {
+        IpAddr::V6(addr) => Ok(addr),
+        IpAddr::V4(_) => unreachable!("an IPv6 socket has an IPv6 address"),
+    }
+}
+
+/// Returns the files in the directory whose names satisfy the predicate (none if it can't be
+/// read), sorted by name
+fn files(dir: &str, predicate: impl Fn(&str) -> bool) -> Vec<std::path::PathBuf> {
+    let mut files: Vec<_> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| {
+            path.file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(&predicate)
+        })
+        .collect();
+    files.sort();
+    files
+}
+
+/// Reads the PCP servers from the lease files of the DHCP clients installed, in order of
+/// preference, each one with all of its addresses. The IPv4-mapped addresses advertised by
+/// DHCPv6 are returned as IPv4 ones
+pub fn dhcp_servers() -> Vec<Vec<IpAddr>> {
+    let mut servers = Vec::new();
+    let read_text = |path: &Path| fs::read_to_string(path).unwrap_or_default();
+    let read_bytes = |path: &Path| fs::read(path).unwrap_or_default();
+    let v4 = |server: Vec<Ipv4Addr>| server.into_iter().map(IpAddr::V4).collect::<Vec<_>>();
+    let v6 = |server: Vec<Ipv6Addr>| server.into_iter().map(IpAddr::V6).collect::<Vec<_>>();
+
+    for dir in DHCLIENT_DIRS {
+        for path in files(dir, |name| {
+            name.ends_with(".leases") || name.ends_with(".lease")
+        }) {
+            let (servers_v4, servers_v6) = dhcp::parse_dhclient(&read_text(&path));
+            servers.extend(servers_v4.into_iter().map(v4));
+            servers.extend(servers_v6.into_iter().map(v6));
+        }
+    }
+    for dir in DHCPCD_DIRS {
+        for path in files(dir, |name| name.ends_with(".lease")) {
+            servers.extend(dhcp::parse_dhcpcd(&read_bytes(&path)).into_iter().map(v4));
+        }
+        for path in files(dir, |name| name.ends_with(".lease6")) {
+            servers.extend(dhcp::parse_dhcpcd6(&read_bytes(&path)).into_iter().map(v6));
+        }
+    }
+
+    let mut unique = Vec::new();
+    for server in servers {
+        let server: Vec<_> = server
+            .into_iter()
+            .map(|addr| match addr {
+                IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(addr, IpAddr::V4),
+                addr => addr,
+            })
+            .collect();
+        if !unique.contains(&server) {
+            unique.push(server);
+        }
+    }
+    unique
+}
+
+/// Returns the first address of each server that satisfies the filter, without duplicates
+fn first_addresses<T: PartialEq>(
+    servers: Vec<Vec<IpAddr>>,
+    filter: impl Fn(IpAddr) -> Option<T>,
+) -> Vec<T> {
+    let mut addresses = Vec::new();
+    for addr in servers
+        .into_iter()
+        .filter_map(|server| server.into_iter().find_map(&filter))
+    {
+        if !addresses.contains(&addr) {
+            addresses.push(addr);
+        }
+    }
+    addresses
+}
+
+/// Finds the IPv4 PCP servers, in order of preference: the ones advertised by DHCP (using the
+/// first IPv4 address of each one) or, if there isn't any, the default gateway
+pub fn servers_v4() -> io::Result<Vec<Ipv4Addr>> {
+    let servers = first_addresses(dhcp_servers(), |addr| match addr {
+        IpAddr::V4(addr) => Some(addr),
+        IpAddr::V6(_) => None,
+    });
+    match servers.is_empty() {
+        true => Ok(vec![route::default_gateway_v4()?]),
+        false => Ok(servers),
+    }
+}
+
+/// Finds the IPv6 PCP servers, in order of preference, along with the index of the interface
+/// they are on: the ones advertised by DHCP (using the first IPv6 address of each one) or, if
+/// there isn't any, the default gateway
+pub fn servers_v6() -> io::Result<Vec<(Ipv6Addr, u32)>> {
+    let servers = first_addresses(dhcp_servers(), |addr| match addr {
+        IpAddr::V6(addr) => Some((addr, 0)),
+        IpAddr::V4(_) => None,
+    });
+    match servers.is_empty() {
+        true => Ok(vec![route::default_gateway_v6()?]),
+        false => Ok(servers),
+    }
+}
@@ -0,0 +1,165 @@
+//! The default gateway, read from the routing tables of the kernel.
+//!
+//! On Linux the routing tables are exposed in `/proc/net/route` (IPv4) and
+//! `/proc/net/ipv6_route` (IPv6): the default route is the one towards the
+//! unspecified network (with a prefix of length 0) that goes through a gateway, if
+//! there are more the one with the lowest metric is used.
+
+use std::fs;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+/// The route goes through a gateway (`RTF_GATEWAY`)
+const RTF_GATEWAY: u32 = 0x2;
+
+/// Path of the IPv4 routing table
+const ROUTE: &str = "/proc/net/route";
+/// Path of the IPv6 routing table
+const IPV6_ROUTE: &str = "/proc/net/ipv6_route";
+
+/// The error returned when there is no default route
+fn no_route() -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, "no default gateway found")
+}
+
+/// Parses the content of `/proc/net/route`, returning the gateway of the default route (with
+/// the lowest metric) and the name of its interface
+pub fn parse_route(table: &str) -> Option<(Ipv4Addr, String)> {
+    // The first line is the header:
+    // Iface Destination Gateway Flags RefCnt Use Metric Mask MTU Window IRTT
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            let hex = |i: usize| fields.get(i).and_then(|f| u32::from_str_radix(f, 16).ok());
+            let (destination, gateway, flags) = (hex(1)?, hex(2)?, hex(3)?);
+            let (metric, mask) = (fields.get(6)?.parse::<u32>().ok()?, hex(7)?);
+            if destination != 0 || mask != 0 || flags & RTF_GATEWAY == 0 {
+                return None;
+            }
+            // The addresses are written in the byte order of the host
+            let gateway = Ipv4Addr::from(gateway.to_ne_bytes());
+            Some((metric, gateway, fields[0].to_owned()))
+        })
+        .min_by_key(|(metric, _, _)| *metric)
+        .map(|(_, gateway, interface)| (gateway, interface))
+}
+
+/// Parses the content of `/proc/net/ipv6_route`, returning the gateway of the default route
+/// (with the lowest metric) and the name of its interface
+pub fn parse_ipv6_route(table: &str) -> Option<(Ipv6Addr, String)> {
+    // There is no header, the fields are:
+    // destination prefix-length source prefix-length next-hop metric refcnt use flags iface
+    table
+        .lines()
+        .filter_map(|line| {
+            let fields: Vec<_> = line.split_whitespace().collect();
+            if fields.len() < 10 {
+                return None;
+            }
+            let destination = u128::from_str_radix(fields[0], 16).ok()?;
+            let prefix = u8::from_str_radix(fields[1], 16).ok()?;
+            let next_hop = u128::from_str_radix(fields[4], 16).ok()?;
+            let metric = u32::from_str_radix(fields[5], 16).ok()?;
+            let flags = u32::from_str_radix(fields[8], 16).ok()?;
+            if destination != 0 || prefix != 0 || next_hop == 0 || flags & RTF_GATEWAY == 0 {
+                return None;
+            }
+            Some((metric, Ipv6Addr::from(next_hop), fields[9].to_owned()))
+        })
+        .min_by_key(|(metric, _, _)| *metric)
+        .map(|(_, gateway, interface)| (gateway, interface))
+}
+
+/// Returns the index of the network interface with the specified name
+fn interface_index(interface: &str) -> io::Result<u32> {
+    let index = fs::read_to_string(format!("/sys/class/net/{}/ifindex", interface))?;
+    index
+        .trim()
+        .parse()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid interface index"))
+}
+
+/// Reads the gateway of the IPv4 default route
+pub fn default_gateway_v4() -> io::Result<Ipv4Addr> {
+    let table = fs::read_to_string(ROUTE)?;
+    let (gateway, _) = parse_route(&table).ok_or_else(no_route)?;
+    Ok(gateway)
+}
+
+/// Reads the gateway of the IPv6 default route, along with the index of its interface
+pub fn default_gateway_v6() -> io::Result<(Ipv6Addr, u32)> {
+    let table = fs::read_to_string(IPV6_ROUTE)?;
+    let (gateway, interface) = parse_ipv6_route(&table).ok_or_else(no_route)?;
+    Ok((gateway, interface_index(&interface)?))
+}
+
+// The addresses in `/proc/net/route` are in the byte order of the host, the fixtures are the
+// ones of a little endian machine
+#[cfg(all(test, target_endian = "little"))]
+mod tests {
+    use super::*;
+
+    const ROUTE: &str = "\
+Iface\tDestination\tGateway \tFlags\tRefCnt\tUse\tMetric\tMask\t\tMTU\tWindow\tIRTT
+wlan0\t00000000\t0101A8C0\t0003\t0\t0\t600\t00000000\t0\t0\t0
+eth0\t00000000\tFE01A8C0\t0003\t0\t0\t100\t00000000\t0\t0\t0
+eth0\t0001A8C0\t00000000\t0001\t0\t0\t100\t00FFFFFF\t0\t0\t0
+wlan0\t0001A8C0\t00000000\t0001\t0\t0\t600\t00FFFFFF\t0\t0\t0
+";
+
+    const IPV6_ROUTE: &str = "\
+20010db8000000000000000000000000 40 00000000000000000000000000000000 00 00000000000000000000000000000000 00000100 00000001 00000000 00000001     eth0
+fe800000000000000000000000000000 40 00000000000000000000000000000000 00 00000000000000000000000000000000 00000100 00000001 00000000 00000001     eth0
+00000000000000000000000000000000 00 00000000000000000000000000000000 00 00000000000000000000000000000000 00000001 00000001 00000000 00000003    wlan0
+00000000000000000000000000000000 00 00000000000000000000000000000000 00 fe800000000000000000000000000001 00000400 00000001 00000000 00000003     eth0
+00000000000000000000000000000000 00 00000000000000000000000000000000 00 fe80000000000000021122fffe334455 00000064 00000002 00000000 00000003    wlan0
+00000000000000000000000000000000 00 00000000000000000000000000000000 00 00000000000000000000000000000000 ffffffff 00000001 00000000 00200200       lo
+";
+
+    #[test]
+    fn default_route_with_the_lowest_metric() {
+        let (gateway, interface) = parse_route(ROUTE).unwrap();
+        // FE01A8C0 in little endian
+        assert_eq!(gateway, Ipv4Addr::new(192, 168, 1, 254));
+        assert_eq!(interface, "eth0");
+    }
+
+    #[test]
+    fn gateway_in_host_byte_order() {
+        let table = ROUTE.lines().take(2).collect::<Vec<_>>().join("\n");
+        let (gateway, interface) = parse_route(&table).unwrap();
+        assert_eq!(gateway, Ipv4Addr::new(192, 168, 1, 1));
+        assert_eq!(interface, "wlan0");
+    }
+
+    #[test]
+    fn no_default_route() {
+        // Only the header and the local networks
+        let table: Vec<_> = ROUTE.lines().filter(|l| !l.contains("\t0003\t")).collect();
+        let table = table.join("\n");
+        assert_eq!(parse_route(&table), None);
+        assert_eq!(parse_route(""), None);
+    }
+
+    #[test]
+    fn ipv6_default_route_with_the_lowest_metric() {
+        let (gateway, interface) = parse_ipv6_route(IPV6_ROUTE).unwrap();
+        assert_eq!(
+            gateway,
+            "fe80::211:22ff:fe33:4455".parse::<Ipv6Addr>().unwrap()
+        );
+        assert_eq!(interface, "wlan0");
+    }
+
+    #[test]
+    fn ipv6_route_without_next_hop() {
+        // The default route through wlan0 without a next hop has the lowest metric, but it can't
+        // be the gateway (like the unreachable one through lo)
+        let table: Vec<_> = IPV6_ROUTE.lines().filter(|l| !l.contains("0211")).collect();
+        let (gateway, interface) = parse_ipv6_route(&table.join("\n")).unwrap();
+        assert_eq!(gateway, "fe80::1".parse::<Ipv6Addr>().unwrap());
+        assert_eq!(interface, "eth0");
+    }
+}
@@ -0,0 +1,185 @@
+//! A dual-stack host has a PCP server for each address family (which might also be
+//! the same device), and a `Client` only works with one of them. The
+//! `DualStackClient` starts a `Client` for each family and returns a single
+//! `DualStackHandle`, which sends each mapping to the client of its family.
+//!
+//! The two clients are independent, they share only the channel of the errors, so
+//! the ones of both are received from the same handle. A mapping requested for a
+//! family without a client fails with `Error::NoClient`.
+
+use super::builder::ClientBuilder;
+use super::channel;
+use super::handle::{Error, Errors, Handle, Request, RequestType, ShutdownReport};
+use super::map::Map;
+use super::state::MapHandle;
+use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Starts a PCP client for each address family, that are used through the same
+/// `DualStackHandle`.
+///
+/// # Examples
+///
+/// Request a mapping with IPv4 and one with IPv6 from the servers discovered:
+/**
+
+    let handle = DualStackClient::discover().unwrap();
+
+    let map = InboundMap::<Ipv4Addr>::new(6000, 120).protocol(ProtocolNumber::Tcp);
+    let map_v4 = handle.request(map, RequestType::KeepAlive).unwrap();
+
+    let map = InboundMap::<Ipv6Addr>::new(6000, 120).protocol(ProtocolNumber::Tcp);
+    let map_v6 = handle.request(map, RequestType::KeepAlive).unwrap();
+
+*/
+pub enum DualStackClient {}
+
+impl DualStackClient {
+    /// Starts the clients configured by the builders, at least one of them is needed
+    pub fn start(
+        v4: Option<ClientBuilder<Ipv4Addr>>,
+        v6: Option<ClientBuilder<Ipv6Addr>>,
+    ) -> io::Result<DualStackHandle> {
+        if v4.is_none() && v6.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no client to start",
+            ));
+        }
+        let (to_handle, from_client) = channel::channel();
+        let from_client = Arc::new(from_client);
+        let v4 = match v4 {
+            Some(builder) => {
+                let (tx, thread) = builder.launch(to_handle.clone())?;
+                Some(Handle::new(tx, thread, Arc::clone(&from_client)))
+            }
+            None => None,
+        };
+        let v6 = match v6 {
+            Some(builder) => {
+                let (tx, thread) = builder.launch(to_handle)?;
+                Some(Handle::new(tx, thread, Arc::clone(&from_client)))
+            }
+            None => None,
+        };
+        Ok(DualStackHandle {
+            v4,
+            v6,
+            from_client,
+        })
+    }
+
+    /// Starts a client for each address family whose PCP server is discovered (see
+    /// `Client::discover`), it fails only if none of them is
+    pub fn discover() -> io::Result<DualStackHandle> {
+        match (
+            ClientBuilder::<Ipv4Addr>::discover(),
+            ClientBuilder::<Ipv6Addr>::discover(),
+        ) {
+            (Err(err), Err(_)) => Err(err),
+            (v4, v6) => Self::start(v4.ok(), v6.ok()),
+        }
+    }
+}
+
+/// An handle to the PCP clients of both the address families
+///
+/// Mappings of both families can be requested, and the `MapHandle` returned has
+/// the same family of the mapping. The settings of each client, and its requests
+/// other than the mappings, are reached through its own `Handle` (see `v4` and
+/// `v6`).
+pub struct DualStackHandle {
+    v4: Option<Handle<Ipv4Addr>>,
+    v6: Option<Handle<Ipv6Addr>>,
+    /// Channel of the errors of both the clients
+    from_client: Arc<channel::Receiver<Error>>,
+}
+
+impl DualStackHandle {
+    /// Returns the handle of the IPv4 client, if it has been started
+    pub fn v4(&self) -> Option<&Handle<Ipv4Addr>> {
+        self.v4.as_ref()
+    }
+
+    /// Returns the handle of the IPv6 client, if it has been started
+    pub fn v6(&self) -> Option<&Handle<Ipv6Addr>> {
+        self.v6.as_ref()
+    }
+
+    /// Waits for an error of any of the clients to arrive
+    pub fn wait_err(&self) -> Error {
+        self.from_client.recv().unwrap_or_else(Error::from)
+    }
+
+    /// Returns `Some(Error)` if an error has been received from any of the clients, `None`
+    /// otherwise
+    pub fn poll_err(&self) -> Option<Error> {
+        self.from_client.try_recv().ok()
+    }
+
+    /// Returns a `Stream` of the errors reported by the clients, which ends once both of them
+    /// have stopped
+    pub fn errors(&self) -> Errors<'_> {
+        Errors(&self.from_client)
+    }
+
+    /// Enables (or disables, with `None`) the health-check of both the clients (see
+    /// `Handle::health_check`)
+    pub fn health_check(&self, interval: Option<Duration>) {
+        if let Some(v4) = &self.v4 {
+            v4.health_check(interval);
+        }
+        if let Some(v6) = &self.v6 {
+            v6.health_check(interval);
+        }
+    }
+
+    /// Deletes the mappings of both the clients and stops them, at the same time (see
+    /// `Handle::shutdown`). The reports are returned once both have stopped, unless one of
+    /// them fails
+    pub fn shutdown(self, timeout: Duration) -> Result<DualStackReport, Error> {
+        let v4 = self.v4.map(|v4| {
+            let report = v4.request_shutdown(timeout);
+            (v4, report)
+        });
+        let v6 = self.v6.map(|v6| {
+            let report = v6.request_shutdown(timeout);
+            (v6, report)
+        });
+        let v4 = v4.map(|(v4, report)| v4.finish_shutdown(report));
+        let v6 = v6.map(|(v6, report)| v6.finish_shutdown(report));
+        Ok(DualStackReport {
+            v4: v4.transpose()?,
+            v6: v6.transpose()?,
+        })
+    }
+}
+
+/// The outcome of the shutdown of both the clients (see `DualStackHandle::shutdown`)
+#[derive(Debug)]
+pub struct DualStackReport {
+    /// Report of the IPv4 client, if it was started
+    pub v4: Option<ShutdownReport<Ipv4Addr>>,
+    /// Report of the IPv6 client, if it was started
+    pub v6: Option<ShutdownReport<Ipv6Addr>>,
+}
+
+impl<M: Map<Ipv4Addr>> Request<Ipv4Addr, M> for DualStackHandle
+where
+    Handle<Ipv4Addr>: Request<Ipv4Addr, M>,
+{
+    fn request(&self, map: M, kind: RequestType) -> Result<MapHandle<Ipv4Addr>, Error> {
+        self.v4.as_ref().ok_or(Error::NoClient)?.request(map, kind)
+    }
+}
+
+impl<M: Map<Ipv6Addr>> Request<Ipv6Addr, M> for DualStackHandle
+where
+    Handle<Ipv6Addr>: Request<Ipv6Addr, M>,
+{
+    fn request(&self, map: M, kind: RequestType) -> Result<MapHandle<Ipv6Addr>, Error> {
+        self.v6.as_ref().ok_or(Error::NoClient)?.request(map, kind)
+    }
+}
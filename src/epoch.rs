@@ -0,0 +1,145 @@
+//! The validation of the epoch of the server, as described in the section 8.5 of
+//! the RFC.
+//!
+//! Every response carries the _epoch time_ of the server, the number of seconds
+//! since its state was created. The client remembers the last epoch received along
+//! with the instant it arrived, and when a new one arrives it checks that the time
+//! elapsed for the server roughly matches the time elapsed for the client: if it
+//! doesn't, or if the epoch went back in time, the server lost its state (most
+//! likely it rebooted).
+//!
+//! The two clocks are allowed to drift apart by 1/16 (plus 2 seconds of tolerance),
+//! and the epoch of the server is allowed to wrap around 2^32.
+
+use std::time::Instant;
+
+/// Keeps track of the epoch of a server
+#[derive(Debug, Default)]
+pub struct EpochTracker {
+    /// Last epoch received paired with the instant of when it was received
+    previous: Option<(u32, Instant)>,
+}
+
+impl EpochTracker {
+    /// Creates a tracker that hasn't received any epoch yet
+    pub fn new() -> Self {
+        Self { previous: None }
+    }
+
+    /// Returns the last epoch received
+    pub fn epoch(&self) -> Option<u32> {
+        self.previous.map(|(epoch, _)| epoch)
+    }
+
+    /// Forgets the last epoch received, the next one will be accepted whatever it is
+    pub fn clear(&mut self) {
+        self.previous = None;
+    }
+
+    /// Checks the epoch received at the specified instant against the previous one, returns
+    /// `false` if the server lost its state.
+    ///
+    /// The epoch becomes the new reference in both cases, as the state of the server from
+    /// then on is the one it describes.
+    pub fn update(&mut self, epoch: u32, now: Instant) -> bool {
+        let valid = match self.previous {
+            // If there is no previous epoch, just take this one as correct
+            None => true,
+            Some((prev_epoch, then)) => Self::is_valid(prev_epoch, then, epoch, now),
+        };
+        self.previous = Some((epoch, now));
+        valid
+    }
+
+    /// Tells if the epoch received at the instant `now` is valid according to the previous one
+    fn is_valid(prev_epoch: u32, then: Instant, epoch: u32, now: Instant) -> bool {
+        // The difference is computed modulo 2^32, so an epoch that wrapped around is still
+        // ahead of the previous one, and one that went back in time is a huge (negative) delta
+        let server_delta = epoch.wrapping_sub(prev_epoch) as i32;
+        // The epoch can't be more than one second behind the previous one
+        if server_delta < -1 {
+            return false;
+        }
+        let server_delta = server_delta.max(0) as u64;
+        let client_delta = now.saturating_duration_since(then).as_secs();
+
+        // The elapsed times must roughly correspond
+        client_delta + 2 >= server_delta - server_delta / 16
+            && server_delta + 2 >= client_delta - client_delta / 16
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn first_epoch_is_accepted() {
+        let mut tracker = EpochTracker::new();
+        assert!(tracker.update(12345, Instant::now()));
+        assert_eq!(tracker.epoch(), Some(12345));
+    }
+
+    #[test]
+    fn progress_within_the_skew() {
+        let start = Instant::now();
+        let mut tracker = EpochTracker::new();
+        tracker.update(1000, start);
+        // Exactly in time
+        assert!(tracker.update(1100, start + secs(100)));
+        // The server clock is 1/16 + 2 seconds ahead
+        assert!(tracker.update(1100 + 160 + 10 + 2, start + secs(260)));
+        // The server clock is behind, as much as it's allowed to
+        assert!(tracker.update(1272 + 160 - 10 - 2, start + secs(420)));
+        // The same epoch, or one second behind, a second later
+        assert!(tracker.update(1420, start + secs(421)));
+        assert!(tracker.update(1419, start + secs(421)));
+    }
+
+    #[test]
+    fn progress_outside_the_skew() {
+        let start = Instant::now();
+        let mut tracker = EpochTracker::new();
+        tracker.update(1000, start);
+        // The server clock is too far ahead
+        assert!(!tracker.update(1000 + 160 + 10 + 3, start + secs(160)));
+        // The server clock is too far behind (the previous epoch is still the reference)
+        assert!(!tracker.update(1173 + 160 - 10 - 3, start + secs(320)));
+    }
+
+    #[test]
+    fn epoch_going_backwards() {
+        let start = Instant::now();
+        let mut tracker = EpochTracker::new();
+        tracker.update(5000, start);
+        // The server rebooted
+        assert!(!tracker.update(10, start + secs(10)));
+        // The new epoch is the reference from now on
+        assert_eq!(tracker.epoch(), Some(10));
+        assert!(tracker.update(20, start + secs(20)));
+    }
+
+    #[test]
+    fn epoch_wrapping_around() {
+        let start = Instant::now();
+        let mut tracker = EpochTracker::new();
+        tracker.update(u32::MAX - 5, start);
+        assert!(tracker.update(4, start + secs(10)));
+        // Wrapping backwards is still going back in time
+        assert!(!tracker.update(u32::MAX - 100, start + secs(20)));
+    }
+
+    #[test]
+    fn cleared_tracker_accepts_any_epoch() {
+        let start = Instant::now();
+        let mut tracker = EpochTracker::new();
+        tracker.update(5000, start);
+        tracker.clear();
+        assert!(tracker.update(10, start + secs(10)));
+    }
+}
@@ -1,10 +1,18 @@
+use super::channel;
 use super::event::Event;
-use super::map::{InboundMap, Map, OutboundMap};
+use super::map::{InboundMap, Map, Mapping, OutboundMap};
+use super::session::ServerStatus;
+use super::slab::MappingId;
 use super::state::{AtomicState, MapHandle, State};
 use super::IpAddress;
-use crate::types::ParsingError;
+use crate::types::{ParsingError, ResponsePacket};
+use futures_core::Stream;
+use std::pin::Pin;
 use std::sync::mpsc::{self, RecvError};
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread::JoinHandle;
+use std::time::Duration;
 use std::{fmt, io};
 
 /// Error generated by PCP operations
@@ -21,6 +29,29 @@ pub enum Error {
     /// Warning generated when the server responds with a packet with an unknown
     /// format or some invalid values
     Parsing(ParsingError),
+
+    /// Warning generated when the server sends a MAP or PEER response that doesn't match
+    /// any of the requested mappings
+    UnmatchedResponse(ResponsePacket),
+
+    /// Error generated when the server doesn't respond to an announce request
+    NoResponse,
+
+    /// Error generated when a mapping is requested to a `DualStackHandle` for an address
+    /// family that has no client
+    NoClient,
+
+    /// Warning generated when the mappings can't be saved to the state file (see
+    /// `ClientBuilder::state_file`)
+    Store(io::Error),
+
+    /// Warning generated when a datagram can't be sent to the server (e.g. the network is
+    /// unreachable while the host moves to another one), the requests are retransmitted anyway
+    Send(io::Error),
+
+    /// Error generated when the changes of the network can't be followed anymore (see
+    /// `ClientBuilder::watch_network`)
+    Watch(io::Error),
 }
 
 impl From<io::Error> for Error {
@@ -47,6 +78,12 @@ impl fmt::Display for Error {
             Self::Socket(err) => write!(f, "Socket error: {:?}", err),
             Self::Channel(err) => write!(f, "Inner threads communication error: {:?}", err),
             Self::Parsing(err) => write!(f, "Response parsing error: {:?}", err),
+            Self::UnmatchedResponse(res) => write!(f, "Response matching no request: {:?}", res),
+            Self::NoResponse => write!(f, "The server didn't respond to the announce request"),
+            Self::NoClient => write!(f, "There is no client for the address family"),
+            Self::Store(err) => write!(f, "Failed to save the mappings: {:?}", err),
+            Self::Send(err) => write!(f, "Failed to send a datagram to the server: {:?}", err),
+            Self::Watch(err) => write!(f, "Failed to follow the network changes: {:?}", err),
         }
     }
 }
@@ -91,17 +128,22 @@ impl fmt::Display for Error {
 */
 pub struct Handle<Ip: IpAddress> {
     to_client: mpsc::Sender<Event<Ip>>,
-    from_client: mpsc::Receiver<Error>,
+    /// Channel of the errors, which might be shared with another client (see `DualStackHandle`)
+    from_client: Arc<channel::Receiver<Error>>,
+    /// Main thread of the client, which is joined on shutdown
+    thread: Option<JoinHandle<()>>,
 }
 
 impl<Ip: IpAddress> Handle<Ip> {
     pub(crate) fn new(
         to_client: mpsc::Sender<Event<Ip>>,
-        from_client: mpsc::Receiver<Error>,
+        thread: JoinHandle<()>,
+        from_client: Arc<channel::Receiver<Error>>,
     ) -> Self {
         Handle {
             to_client,
             from_client,
+            thread: Some(thread),
         }
     }
 
@@ -115,9 +157,112 @@ impl<Ip: IpAddress> Handle<Ip> {
         self.from_client.try_recv().ok()
     }
 
-    /// Signals the `Client` to end execution
-    pub fn shutdown(self) {
-        self.to_client.send(Event::Shutdown).ok();
+    /// Returns a `Stream` of the errors reported by the `Client`, which ends once the client
+    /// has stopped
+    pub fn errors(&self) -> Errors<'_> {
+        Errors(&self.from_client)
+    }
+
+    /// Sends an announce request to the server and waits for its response, which tells the
+    /// version of the protocol spoken by the server and its epoch. `Error::NoResponse` is
+    /// returned if the server never responds
+    pub fn announce(&self) -> Result<ServerStatus, Error> {
+        let (tx, rx) = mpsc::channel();
+        self.to_client
+            .send(Event::Announce(tx))
+            .map_err(|_| Error::Channel(RecvError))?;
+        rx.recv()?.ok_or(Error::NoResponse)
+    }
+
+    /// Enables (or disables, with `None`) the health-check: while there are no active mappings
+    /// the `Client` sends an announce request every `interval`, so that a reboot of the server
+    /// is detected even without mappings. If the server stops responding an
+    /// `Error::NoResponse` is reported
+    pub fn health_check(&self, interval: Option<Duration>) {
+        self.to_client.send(Event::HealthCheck(interval)).ok();
+    }
+
+    /// Requests again the mappings of the previous run of the client, saved in its state file
+    /// (see `ClientBuilder::state_file`), with their nonces: the server refreshes them instead
+    /// of creating new ones. The ones that aren't needed anymore can be deleted by dropping
+    /// their handles.
+    ///
+    /// Until they are restored the mappings are kept in the file as they were, only the first
+    /// call returns them
+    pub fn restore(&self) -> Result<Vec<MapHandle<Ip>>, Error> {
+        let (tx, rx) = mpsc::channel();
+        self.to_client
+            .send(Event::Restore(tx))
+            .map_err(|_| Error::Channel(RecvError))?;
+        rx.recv()?
+            .into_iter()
+            .map(|stored| self.request(stored.mapping, stored.kind))
+            .collect()
+    }
+
+    /// Deletes all the mappings and stops the `Client`: the server is given up to `timeout` to
+    /// confirm the deletions, then all the threads of the client are stopped and joined. The
+    /// returned report tells which deletions have been confirmed
+    pub fn shutdown(self, timeout: Duration) -> Result<ShutdownReport<Ip>, Error> {
+        let report = self.request_shutdown(timeout);
+        self.finish_shutdown(report)
+    }
+
+    /// Signals the `Client` to delete the mappings and end execution, the report is sent
+    /// through the returned channel
+    pub(crate) fn request_shutdown(&self, timeout: Duration) -> mpsc::Receiver<ShutdownReport<Ip>> {
+        let (tx, rx) = mpsc::channel();
+        self.to_client
+            .send(Event::GracefulShutdown(timeout, tx))
+            .ok();
+        rx
+    }
+
+    /// Waits for the report of the shutdown requested and for the `Client` to end
+    pub(crate) fn finish_shutdown(
+        mut self,
+        report: mpsc::Receiver<ShutdownReport<Ip>>,
+    ) -> Result<ShutdownReport<Ip>, Error> {
+        let report = report.recv();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+        Ok(report?)
+    }
+}
+
+/// The outcome of the deletion of the mappings when a client is shut down (see
+/// `Handle::shutdown`), each mapping is identified by its id (see `MapHandle::id` and
+/// `AsyncMapHandle::id`) and described as it was requested
+#[derive(Debug)]
+pub struct ShutdownReport<Ip: IpAddress> {
+    /// Mappings whose deletion has been confirmed by the server
+    pub confirmed: Vec<(MappingId, Mapping<Ip>)>,
+    /// Mappings whose deletion hasn't been confirmed in time
+    pub unconfirmed: Vec<(MappingId, Mapping<Ip>)>,
+}
+
+impl<Ip: IpAddress> ShutdownReport<Ip> {
+    /// Splits the deleted mappings in the confirmed and the unconfirmed ones
+    pub(crate) fn new(deleted: Vec<(MappingId, Mapping<Ip>)>, confirmed: &[MappingId]) -> Self {
+        let (confirmed, unconfirmed) = deleted
+            .into_iter()
+            .partition(|(id, _)| confirmed.contains(id));
+        Self {
+            confirmed,
+            unconfirmed,
+        }
+    }
+}
+
+/// A `Stream` of the errors reported by a `Client` (see `Handle::errors`)
+pub struct Errors<'a>(pub(crate) &'a channel::Receiver<Error>);
+
+impl Stream for Errors<'_> {
+    type Item = Error;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Error>> {
+        self.0.poll_recv(cx)
     }
 }
 
@@ -126,7 +271,7 @@ impl<Ip: IpAddress> Handle<Ip> {
 /// - `Once`: send only one time
 /// - `Repeat(n)`: repeats for `n` times
 /// - `KeepAlive`: continues to resend until it gets stopped manually
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum RequestType {
     Once,
     Repeat(usize),
@@ -144,7 +289,7 @@ pub trait Request<Ip: IpAddress, M: Map<Ip>> {
 impl<Ip: IpAddress> Request<Ip, InboundMap<Ip>> for Handle<Ip> {
     fn request(&self, map: InboundMap<Ip>, kind: RequestType) -> Result<MapHandle<Ip>, Error> {
         let (id_tx, id_rx) = mpsc::channel();
-        let (alert_tx, alert_rx) = mpsc::channel();
+        let (alert_tx, alert_rx) = channel::channel();
         let state = Arc::new(AtomicState::new(State::Requested));
         self.to_client
             .send(Event::InboundMap(
@@ -166,7 +311,7 @@ impl<Ip: IpAddress> Request<Ip, InboundMap<Ip>> for Handle<Ip> {
 impl<Ip: IpAddress> Request<Ip, OutboundMap<Ip>> for Handle<Ip> {
     fn request(&self, map: OutboundMap<Ip>, kind: RequestType) -> Result<MapHandle<Ip>, Error> {
         let (id_tx, id_rx) = mpsc::channel();
-        let (alert_tx, alert_rx) = mpsc::channel();
+        let (alert_tx, alert_rx) = channel::channel();
         let state = Arc::new(AtomicState::new(State::Requested));
         self.to_client
             .send(Event::OutboundMap(
@@ -185,8 +330,22 @@ impl<Ip: IpAddress> Request<Ip, OutboundMap<Ip>> for Handle<Ip> {
     }
 }
 
+impl<Ip: IpAddress> Request<Ip, Mapping<Ip>> for Handle<Ip> {
+    fn request(&self, map: Mapping<Ip>, kind: RequestType) -> Result<MapHandle<Ip>, Error> {
+        match map {
+            Mapping::Inbound(map) => self.request(map, kind),
+            Mapping::Outbound(map) => self.request(map, kind),
+        }
+    }
+}
+
 impl<Ip: IpAddress> Drop for Handle<Ip> {
+    /// Stops the `Client` right away, without deleting the mappings, and waits for all its
+    /// threads to end
     fn drop(&mut self) {
         self.to_client.send(Event::Shutdown).ok();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
     }
 }
@@ -1,10 +1,10 @@
 //! A PCP client implementation written in Rust.
 //!
 //! > The Port Control Protocol allows an IPv6 or IPv4 host to control how
-//! incoming IPv6 or IPv4 packets are translated and forwarded by a
-//! Network Address Translator (NAT) or simple firewall.
-//! The aim of this protocol is to replace the older NAT-PMP by allowing
-//! a host to optimize its outgoing NAT keepalive messages.
+//! > incoming IPv6 or IPv4 packets are translated and forwarded by a
+//! > Network Address Translator (NAT) or simple firewall.
+//! > The aim of this protocol is to replace the older NAT-PMP by allowing
+//! > a host to optimize its outgoing NAT keepalive messages.
 //! >
 //! >~ *from [RFC 6887](https://tools.ietf.org/html/rfc6887)*
 //!
@@ -13,7 +13,8 @@
 //! To start requesting mappings you first have to start the `Client` and get an
 //! `Handle` to it. Once you have the `Handle` you can start creating requests.
 //!
-//! ```rust
+//! ```rust,no_run
+//! # use pcp::Client;
 //! use std::net::Ipv4Addr;
 //!
 //! // This is the address of your host in your local network
@@ -26,16 +27,45 @@
 //! let handle = Client::<Ipv4Addr>::start(pcp_client, pcp_server).unwrap();
 //! ```
 //!
+//! The `Client` started this way uses the default settings, a `ClientBuilder` can
+//! be used instead to change the ports, the options of the sockets and the timing
+//! of the requests.
+//!
+//! On Linux the PCP server can also be discovered: `Client::discover` uses the
+//! server advertised by DHCP, reading the leases of the DHCP client, or the default
+//! gateway, reading the routing table of the system. The `discovery` module has the
+//! functions used to find them, and `Client::with_servers` accepts the list of
+//! servers found in some other way.
+//!
+//! In both cases the address of the client is the one the system uses to reach
+//! the server, and when there are more servers they are tried in order until one
+//! of them responds, as described by RFC 7488.
+//!
+//! With the `netlink` feature, on Linux, the client can follow the changes of the
+//! network (see `ClientBuilder::watch_network`), so that the mappings are
+//! requested again when the host gets a different address.
+//!
+//! The nonces of the mappings, which are needed to refresh or delete them, can
+//! be saved in a file (see `ClientBuilder::state_file`) so that, after a restart,
+//! the mappings of the previous run are requested again with `Handle::restore`
+//! instead of lingering on the server until they expire.
+//!
+//! A `Client` works with a single address family, on a dual-stack host the
+//! `DualStackClient` starts one for each family and returns a `DualStackHandle`
+//! that accepts the mappings of both.
+//!
 //! There are two types of mappings you can request: `InboundMapping`s and
 //! `OutboundMapping`s (The difference is explained later). Both of them can be
 //! contructed with the `new` method and support a various number of options that
 //! can be added with chaining methods.
 //!
 //! ```rust
+//! # use pcp::{InboundMap, ProtocolNumber};
+//! # use std::net::Ipv4Addr;
 //! // This allows any host from outside the local network to send requests to
 //! // your computer using the TCP protocol on the port 6000.
 //! // Once requested, it will last for 20 seconds
-//! let mapping = InboundMap::new(6000, 20).protocol(ProtocolNumber::Tcp);
+//! let mapping = InboundMap::<Ipv4Addr>::new(6000, 20).protocol(ProtocolNumber::Tcp);
 //! ```
 //!
 //! After you have a mapping you can request it by calling the `request` method on
@@ -45,68 +75,135 @@
 //! keeping it alive until it gets blocked explicitly. A `MappingHandle` can be
 //! used to control the mapping and, also, to check its state.
 //!
-//! ```rust
+//! ```rust,no_run
+//! # use pcp::{Client, InboundMap, ProtocolNumber, Request, RequestType};
+//! # use std::net::Ipv4Addr;
+//! # let handle = Client::<Ipv4Addr>::start([192, 168, 1, 101].into(), [192, 168, 1, 1].into()).unwrap();
+//! # let mapping = InboundMap::new(6000, 20).protocol(ProtocolNumber::Tcp);
 //! // Request the mapping to the server and instruct the client to keeping
 //! // it alive for as long as I want
-//! let map_handle = handle.request(mapping, RequestType::KeepAlive);
+//! let map_handle = handle.request(mapping, RequestType::KeepAlive).unwrap();
 //!
 //! // do stuff...
 //!
 //! map_handle.revoke(); // stop the mapping
 //! ```
 //!
+//! # Custom Event Loops
+//!
+//! The `Client` is just a driver for a `PcpSession`, which implements the whole
+//! protocol without doing any I/O. If you already have an event loop you can feed
+//! the session with the datagrams received from the server and with the timeouts,
+//! and send the datagrams it produces yourself.
+//!
+//! With the `tokio` feature enabled, the `AsyncClient` drives the session from a
+//! tokio task: mappings are requested with an `async` method and the alerts of an
+//! `AsyncMapHandle` can be consumed as a `Stream`.
+//!
+//! The handles of the threaded `Client` can be awaited too, from any runtime:
+//! `MapHandle::alerts` and `Handle::errors` return a `Stream`, and
+//! `MapHandle::assigned` a `Future` that resolves once the server assigns the
+//! external address of the mapping.
+//!
+//! # NAT-PMP
+//!
+//! If the server turns out to be a NAT-PMP gateway (or it doesn't support the
+//! version of PCP used) the client falls back to NAT-PMP on its own. The mappings
+//! are handled in the same way, but only inbound UDP and TCP mappings without
+//! options can be requested. The packets of NAT-PMP are in the `natpmp` module.
+//!
 //! # Difference Between Mappings
 //!
 //! The [RFC](https://tools.ietf.org/html/rfc6887) explains:
 //! > While all mappings are, by necessity, bidirectional (most Internet
-//! communication requires information to flow in both directions for successful
-//! operation), when talking about mappings, it can be helpful to identify them
-//! loosely according to their *primary* purpose.
+//! > communication requires information to flow in both directions for successful
+//! > operation), when talking about mappings, it can be helpful to identify them
+//! > loosely according to their *primary* purpose.
 //! >
 //! > - **Outbound mappings** exist primarily to enable outbound communication.
-//! For example, when a host calls connect() to make an outbound connection, a NAT
-//! gateway will create an implicit dynamic outbound mapping to facilitate that
-//! outbound communication.
+//! > For example, when a host calls connect() to make an outbound connection, a NAT
+//! > gateway will create an implicit dynamic outbound mapping to facilitate that
+//! > outbound communication.
 //! >
 //! > -  **Inbound mappings** exist primarily to enable listening servers to
-//! receive inbound connections.  Generally, when a client calls listen() to listen
-//! for inbound connections, a NAT gateway will not implicitly create any mapping
-//! to facilitate that inbound communication.  A PCP MAP request can be used
-//! explicitly to create a dynamic inbound mapping to enable the desired inbound
-//! communication.
+//! > receive inbound connections.  Generally, when a client calls listen() to listen
+//! > for inbound connections, a NAT gateway will not implicitly create any mapping
+//! > to facilitate that inbound communication.  A PCP MAP request can be used
+//! > explicitly to create a dynamic inbound mapping to enable the desired inbound
+//! > communication.
 
 // TODO: expand documentation
 
 #![allow(unused)]
+#[cfg(feature = "tokio")]
+mod async_client;
+mod builder;
+mod channel;
 mod client;
+pub mod discovery;
+mod dual_stack;
+mod epoch;
 mod event;
 mod handle;
 mod map;
+pub mod natpmp;
+#[cfg(all(feature = "netlink", target_os = "linux"))]
+pub mod netlink;
+mod retry;
+mod session;
+mod slab;
 mod state;
+mod store;
+mod timer;
 pub mod types;
 
+#[cfg(feature = "tokio")]
+pub use async_client::{AsyncClient, AsyncMapHandle};
+pub use builder::ClientBuilder;
 pub use client::Client;
-pub use handle::{Error, Handle, Request, RequestType};
-pub use map::{InboundMap, OutboundMap};
-pub use state::{Alert, MapHandle, State};
+pub use dual_stack::{DualStackClient, DualStackHandle, DualStackReport};
+pub use handle::{Error, Errors, Handle, Request, RequestType, ShutdownReport};
+pub use map::{InboundMap, Map, Mapping, OutboundMap};
+pub use retry::{RetryPolicy, RfcPolicy};
+pub use session::{PcpSession, ServerStatus, SessionEvent};
+pub use slab::MappingId;
+pub use state::{Alert, Alerts, Assigned, MapHandle, PeerAssignment, State};
+pub use store::{StateStore, StoredMapping};
 pub use types::ProtocolNumber;
 
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
 
 /// Common trait for IPv4 and IPv6 addresses
-pub trait IpAddress: std::fmt::Debug + Send + Copy + Into<IpAddr> + 'static {
+pub trait IpAddress: std::fmt::Debug + Send + Copy + PartialEq + Into<IpAddr> + 'static {
     /// Number of bits of the address
     const LENGTH: u8;
     /// Unspeficied address
     const UNSPECIFIED: Self;
+
+    /// Returns the address if it's of this family
+    fn from_ip(addr: IpAddr) -> Option<Self>;
 }
 
 impl IpAddress for Ipv4Addr {
     const LENGTH: u8 = 32;
     const UNSPECIFIED: Self = Self::UNSPECIFIED;
+
+    fn from_ip(addr: IpAddr) -> Option<Self> {
+        match addr {
+            IpAddr::V4(addr) => Some(addr),
+            IpAddr::V6(_) => None,
+        }
+    }
 }
 
 impl IpAddress for Ipv6Addr {
     const LENGTH: u8 = 128;
     const UNSPECIFIED: Self = Self::UNSPECIFIED;
+
+    fn from_ip(addr: IpAddr) -> Option<Self> {
+        match addr {
+            IpAddr::V6(addr) => Some(addr),
+            IpAddr::V4(_) => None,
+        }
+    }
 }
@@ -1,19 +1,103 @@
 use super::IpAddress;
-use crate::types::ProtocolNumber;
+use crate::types::payloads::{OptionPayload, RequestPayload};
+use crate::types::{ProtocolNumber, RequestPacket};
+use std::net::IpAddr;
 
 /// Trait used to generalize any type of mapping
-pub trait Map<Ip: IpAddress> {}
+pub trait Map<Ip: IpAddress>: Into<Mapping<Ip>> {}
 impl<Ip: IpAddress> Map<Ip> for InboundMap<Ip> {}
 impl<Ip: IpAddress> Map<Ip> for OutboundMap<Ip> {}
+impl<Ip: IpAddress> Map<Ip> for Mapping<Ip> {}
 
-#[derive(Clone, Debug)]
+/// Any type of mapping
+#[derive(Clone, PartialEq, Debug)]
+pub enum Mapping<Ip: IpAddress> {
+    Inbound(InboundMap<Ip>),
+    Outbound(OutboundMap<Ip>),
+}
+
+impl<Ip: IpAddress> Mapping<Ip> {
+    /// Rebuilds the mapping from its request, with the same nonce and the lifetime of the
+    /// request. Returns `None` if it's not the request of a mapping of this address family
+    pub(crate) fn from_request(request: &RequestPacket) -> Option<Self> {
+        let lifetime = request.header.lifetime;
+        let (mut third_party, mut filters, mut prefer_failure) = (None, Vec::new(), false);
+        for option in &request.options {
+            match &option.payload {
+                OptionPayload::Filter(f) => filters.push(Filter {
+                    remote_port: f.remote_port,
+                    remote_addr: Ip::from_ip(f.remote_address)?,
+                    // The prefix is always relative to an IPv6 address
+                    prefix: f.prefix.checked_sub(128 - Ip::LENGTH)?,
+                }),
+                OptionPayload::ThidParty(p) => third_party = Some(Ip::from_ip(p.address)?),
+                OptionPayload::PreferFailure => prefer_failure = true,
+                OptionPayload::Raw(_) => (),
+            }
+        }
+        // The unspecified address and the port 0 mean that nothing was suggested
+        let suggested = |addr: IpAddr, port: u16| {
+            let addr = Some(addr)
+                .filter(|addr| !addr.is_unspecified())
+                .and_then(Ip::from_ip);
+            (addr, Some(port).filter(|&port| port != 0))
+        };
+        // Hopopt (0) means all the protocols
+        let protocol = |protocol| Some(protocol).filter(|&p| p != ProtocolNumber::Hopopt);
+        match &request.payload {
+            RequestPayload::Map(p) => {
+                let (external_addr, external_port) = suggested(p.external_address, p.external_port);
+                Some(Self::Inbound(InboundMap {
+                    lifetime,
+                    internal_port: p.internal_port,
+                    protocol: protocol(p.protocol),
+                    third_party,
+                    external_port,
+                    external_addr,
+                    filters,
+                    prefer_failure,
+                    nonce: Some(p.nonce),
+                }))
+            }
+            RequestPayload::Peer(p) => {
+                let (external_addr, external_port) = suggested(p.external_address, p.external_port);
+                Some(Self::Outbound(OutboundMap {
+                    lifetime,
+                    internal_port: p.internal_port,
+                    remote_addr: Ip::from_ip(p.remote_address)?,
+                    remote_port: p.remote_port,
+                    protocol: protocol(p.protocol),
+                    third_party,
+                    external_port,
+                    external_addr,
+                    nonce: Some(p.nonce),
+                }))
+            }
+            RequestPayload::Announce => None,
+        }
+    }
+}
+
+impl<Ip: IpAddress> From<InboundMap<Ip>> for Mapping<Ip> {
+    fn from(map: InboundMap<Ip>) -> Self {
+        Self::Inbound(map)
+    }
+}
+
+impl<Ip: IpAddress> From<OutboundMap<Ip>> for Mapping<Ip> {
+    fn from(map: OutboundMap<Ip>) -> Self {
+        Self::Outbound(map)
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
 pub struct Filter<Ip: IpAddress> {
     pub remote_port: u16,
     pub remote_addr: Ip,
     pub prefix: u8,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, PartialEq, Debug)]
 /// An inbound map, is used to create an explicit dynamic mapping between an Internal Address +
 /// Port and an External Address + Port.
 pub struct InboundMap<Ip: IpAddress> {
@@ -25,6 +109,7 @@ pub struct InboundMap<Ip: IpAddress> {
     pub(crate) external_addr: Option<Ip>,
     pub(crate) filters: Vec<Filter<Ip>>,
     pub(crate) prefer_failure: bool,
+    pub(crate) nonce: Option<[u8; 12]>,
 }
 
 impl<Ip: IpAddress> InboundMap<Ip> {
@@ -40,6 +125,7 @@ impl<Ip: IpAddress> InboundMap<Ip> {
             external_addr: None,
             filters: Vec::new(),
             prefer_failure: false,
+            nonce: None,
         }
     }
 
@@ -100,10 +186,23 @@ impl<Ip: IpAddress> InboundMap<Ip> {
         });
         self
     }
+
+    /// Specifies the nonce of the mapping, instead of letting the client generate a random one
+    /// (for example to refresh a mapping requested in the past).
+    ///
+    /// The nonce is the proof that the mapping belongs to this client, so it should be
+    /// unpredictable
+    pub fn nonce(mut self, nonce: [u8; 12]) -> Self {
+        match self.nonce {
+            Some(_) => panic!("The mapping nonce was already specified"),
+            None => self.nonce = Some(nonce),
+        }
+        self
+    }
 }
 
 /// An outbound map is used to create a new dynamic mapping to a remote peer's IP address and port
-#[derive(Clone, Debug)]
+#[derive(Clone, PartialEq, Debug)]
 pub struct OutboundMap<Ip: IpAddress> {
     pub(crate) lifetime: u32,
     pub(crate) internal_port: u16,
@@ -113,6 +212,7 @@ pub struct OutboundMap<Ip: IpAddress> {
     pub(crate) third_party: Option<Ip>,
     pub(crate) external_port: Option<u16>,
     pub(crate) external_addr: Option<Ip>,
+    pub(crate) nonce: Option<[u8; 12]>,
 }
 
 impl<Ip: IpAddress> OutboundMap<Ip> {
@@ -127,6 +227,7 @@ impl<Ip: IpAddress> OutboundMap<Ip> {
             third_party: None,
             external_port: None,
             external_addr: None,
+            nonce: None,
         }
     }
 
@@ -167,4 +268,17 @@ impl<Ip: IpAddress> OutboundMap<Ip> {
         }
         self
     }
+
+    /// Specifies the nonce of the mapping, instead of letting the client generate a random one
+    /// (for example to refresh a mapping requested in the past).
+    ///
+    /// The nonce is the proof that the mapping belongs to this client, so it should be
+    /// unpredictable
+    pub fn nonce(mut self, nonce: [u8; 12]) -> Self {
+        match self.nonce {
+            Some(_) => panic!("The mapping nonce was already specified"),
+            None => self.nonce = Some(nonce),
+        }
+        self
+    }
 }
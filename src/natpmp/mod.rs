@@ -0,0 +1,30 @@
+//! This module defines the packets of NAT-PMP, the protocol that PCP replaced.
+//!
+//! > The NAT Port Mapping Protocol (NAT-PMP) allows a computer in a private network
+//! > (behind a NAT router) to automatically configure the router to allow parties
+//! > outside the private network to contact it.
+//! >
+//! >~ *from [RFC 6886](https://tools.ietf.org/html/rfc6886)*
+//!
+//! NAT-PMP uses the same port of PCP and its packets start with a version number
+//! of 0, so a PCP client can recognize a NAT-PMP server by its responses and fall
+//! back to it (see the `session` module).
+//!
+//! The types follow the same conventions of the ones in the `types` module: the
+//! requests have a `bytes` method and the responses have a -Slice counterpart that
+//! can be obtained via the `try_from` method and then be parsed (see `Parsable`).
+
+mod op_code;
+mod request;
+mod response;
+mod result_code;
+
+pub use op_code::OpCode;
+pub use request::{ExternalAddressRequest, MappingRequest};
+pub use response::{
+    MappingResponsePayload, Response, ResponseHeader, ResponsePayload, ResponseSlice,
+};
+pub use result_code::ResultCode;
+
+/// The version number of NAT-PMP
+pub const VERSION: u8 = 0;
@@ -0,0 +1,39 @@
+use crate::types::{ParsingError, ProtocolNumber};
+use std::convert::TryFrom;
+
+/// The `OpCode` field contained in the NAT-PMP requests and responses.
+///
+/// _On responses_: it's the one of the request it's responding to plus 128.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OpCode {
+    /// Requests the external address of the NAT gateway
+    ExternalAddress = 0,
+    /// Requests an UDP mapping
+    MapUdp = 1,
+    /// Requests a TCP mapping
+    MapTcp = 2,
+}
+
+impl OpCode {
+    /// Returns the opcode used for mapping the specified protocol, if NAT-PMP supports it
+    pub fn map(protocol: ProtocolNumber) -> Option<Self> {
+        match protocol {
+            ProtocolNumber::Udp => Some(Self::MapUdp),
+            ProtocolNumber::Tcp => Some(Self::MapTcp),
+            _ => None,
+        }
+    }
+}
+
+impl TryFrom<u8> for OpCode {
+    type Error = ParsingError;
+
+    fn try_from(val: u8) -> Result<Self, Self::Error> {
+        match val {
+            0 => Ok(Self::ExternalAddress),
+            1 => Ok(Self::MapUdp),
+            2 => Ok(Self::MapTcp),
+            n => Err(ParsingError::NotAnOpCode(n)),
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! # Format
+//!
+//! The RFC defines the following format for the external address request:
+/*!
+```text
+     0                   1
+     0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5
+    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+    | Vers = 0      | OP = 0        |
+    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+```
+*/
+//! and the following one for the mapping request:
+/*!
+```text
+     0                   1                   2                   3
+     0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+    | Vers = 0      | OP = x        | Reserved                      |
+    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+    | Internal Port                 | Suggested External Port       |
+    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+    | Requested Port Mapping Lifetime in Seconds                    |
+    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+```
+*/
+//! **OP**: 1 for UDP mappings and 2 for TCP mappings.
+//!
+//! **Reserved**: MUST be set to zero on transmission and MUST be ignored on reception.
+//!
+//! **Suggested External Port**: The external port the client would like to have, the
+//!     gateway is not obliged to assign it. A mapping is deleted by requesting it
+//!     with a lifetime of 0 and a suggested external port of 0.
+
+use super::{OpCode, VERSION};
+use crate::types::payloads::{OptionPayload, RequestPayload};
+use crate::types::{RequestPacket, ResultCode};
+use std::convert::TryFrom;
+
+/// A NAT-PMP request of the external address of the gateway
+#[derive(PartialEq, Debug)]
+pub struct ExternalAddressRequest;
+
+impl ExternalAddressRequest {
+    /// Size of the NAT-PMP external address request (in bytes)
+    pub const SIZE: usize = 2;
+
+    /// Creates a correctly formatted byte array representing the request
+    pub fn bytes(&self) -> [u8; Self::SIZE] {
+        [VERSION, OpCode::ExternalAddress as u8]
+    }
+}
+
+/// A correctly formed NAT-PMP mapping request, containing the `OpCode` of the protocol
+/// to map, the internal port, the suggested external port and the requested lifetime
+#[derive(PartialEq, Debug)]
+pub struct MappingRequest {
+    pub opcode: OpCode,
+    pub internal_port: u16,
+    pub external_port: u16,
+    pub lifetime: u32,
+}
+
+impl MappingRequest {
+    /// Size of the NAT-PMP mapping request (in bytes)
+    pub const SIZE: usize = 12;
+
+    /// Creates a correctly formatted byte array representing the request
+    #[rustfmt::skip]
+    pub fn bytes(&self) -> [u8; Self::SIZE] {
+        let int_port = self.internal_port.to_be_bytes();
+        let ext_port = self.external_port.to_be_bytes();
+        let lifetime = self.lifetime.to_be_bytes();
+        [
+            VERSION, self.opcode as u8, 0, 0,
+            int_port[0], int_port[1], ext_port[0], ext_port[1],
+            lifetime[0], lifetime[1], lifetime[2], lifetime[3],
+        ]
+    }
+}
+
+impl TryFrom<&RequestPacket> for MappingRequest {
+    type Error = ResultCode;
+
+    /// Translates a PCP request into the equivalent NAT-PMP one, the error is the PCP result
+    /// code that describes why it can't be done
+    fn try_from(request: &RequestPacket) -> Result<Self, Self::Error> {
+        // NAT-PMP can only create inbound mappings
+        let payload = match &request.payload {
+            RequestPayload::Map(payload) => payload,
+            _ => return Err(ResultCode::UnsuppCode),
+        };
+        // and only for UDP or TCP
+        let opcode = OpCode::map(payload.protocol).ok_or(ResultCode::UnsuppProtocol)?;
+        // There are no options in NAT-PMP, only the optional ones can be left out
+        let mandatory = request.options.iter().any(|o| match o.payload {
+            OptionPayload::Raw(_) => !o.header.code.is_optional(),
+            _ => true,
+        });
+        if mandatory {
+            return Err(ResultCode::UnsuppOption);
+        }
+        let lifetime = request.header.lifetime;
+        Ok(Self {
+            opcode,
+            internal_port: payload.internal_port,
+            // A mapping is deleted only if the suggested port is 0 too
+            external_port: if lifetime == 0 {
+                0
+            } else {
+                payload.external_port
+            },
+            lifetime,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{PacketOption, ProtocolNumber};
+    use std::net::{IpAddr, Ipv4Addr};
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 10));
+    const REMOTE: IpAddr = IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1));
+
+    /// Builds a PCP map request of the internal port 6000, suggesting the external port 7000
+    fn map(
+        lifetime: u32,
+        protocol: Option<ProtocolNumber>,
+        options: Vec<PacketOption>,
+    ) -> RequestPacket {
+        let unspecified = Ipv4Addr::UNSPECIFIED.into();
+        RequestPacket::map(
+            2,
+            lifetime,
+            CLIENT,
+            [0; 12],
+            protocol,
+            6000,
+            7000,
+            unspecified,
+            options,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn mapping_request() {
+        let request = MappingRequest::try_from(&map(120, Some(ProtocolNumber::Tcp), vec![]));
+        let request = request.unwrap();
+        assert_eq!(
+            request,
+            MappingRequest {
+                opcode: OpCode::MapTcp,
+                internal_port: 6000,
+                external_port: 7000,
+                lifetime: 120,
+            }
+        );
+        assert_eq!(
+            request.bytes(),
+            [0, 2, 0, 0, 0x17, 0x70, 0x1b, 0x58, 0, 0, 0, 120]
+        );
+
+        let request = MappingRequest::try_from(&map(120, Some(ProtocolNumber::Udp), vec![]));
+        assert_eq!(request.unwrap().opcode, OpCode::MapUdp);
+    }
+
+    #[test]
+    fn deletion_request() {
+        let request = MappingRequest::try_from(&map(0, Some(ProtocolNumber::Udp), vec![]));
+        let request = request.unwrap();
+        assert_eq!((request.lifetime, request.external_port), (0, 0));
+    }
+
+    #[test]
+    fn optional_options_left_out() {
+        let options = vec![PacketOption::raw(200, vec![1, 2, 3, 4])];
+        let request = MappingRequest::try_from(&map(120, Some(ProtocolNumber::Tcp), options));
+        assert!(request.is_ok());
+    }
+
+    #[test]
+    fn untranslatable_requests() {
+        // Every protocol, or one that is not UDP or TCP
+        for protocol in [None, Some(ProtocolNumber::Sctp)].iter() {
+            let request = MappingRequest::try_from(&map(120, *protocol, vec![]));
+            assert_eq!(request, Err(ResultCode::UnsuppProtocol));
+        }
+
+        // Options that can't be left out
+        let options = vec![
+            PacketOption::filter(128, 0, REMOTE),
+            PacketOption::prefer_failure(),
+            PacketOption::third_party(CLIENT),
+            PacketOption::raw(100, vec![1, 2, 3, 4]),
+        ];
+        for option in options {
+            let request = map(120, Some(ProtocolNumber::Tcp), vec![option]);
+            let request = MappingRequest::try_from(&request);
+            assert_eq!(request, Err(ResultCode::UnsuppOption));
+        }
+
+        // Outbound mappings
+        let unspecified = Ipv4Addr::UNSPECIFIED.into();
+        let tcp = Some(ProtocolNumber::Tcp);
+        let request = RequestPacket::peer(
+            2,
+            120,
+            CLIENT,
+            [0; 12],
+            tcp,
+            6000,
+            0,
+            unspecified,
+            80,
+            REMOTE,
+            vec![],
+        );
+        let request = MappingRequest::try_from(&request.unwrap());
+        assert_eq!(request, Err(ResultCode::UnsuppCode));
+    }
+}
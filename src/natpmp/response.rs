@@ -0,0 +1,279 @@
+//! # Format
+//!
+//! The RFC defines the following format for the external address response:
+/*!
+```text
+     0                   1                   2                   3
+     0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+    | Vers = 0      | OP = 128 + 0  | Result Code (net byte order)  |
+    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+    | Seconds Since Start of Epoch (in network byte order)          |
+    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+    | External IPv4 Address (a.b.c.d)                               |
+    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+```
+*/
+//! and the following one for the mapping response:
+/*!
+```text
+     0                   1                   2                   3
+     0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
+    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+    | Vers = 0      | OP = 128 + x  | Result Code                   |
+    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+    | Seconds Since Start of Epoch                                  |
+    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+    | Internal Port                 | Mapped External Port          |
+    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+    | Port Mapping Lifetime in Seconds                              |
+    +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
+```
+*/
+//! **Seconds Since Start of Epoch**: The equivalent of the PCP epoch time.
+//!
+//! **External IPv4 Address**: The external address of the gateway, the same
+//!     response is also multicasted by the gateway when the address changes.
+//!
+//! **Mapped External Port**: The external port assigned by the gateway.
+//!
+//! **Port Mapping Lifetime**: The lifetime assigned by the gateway.
+//!
+//! When the result code is not a success the gateway might send only the first
+//! 8 bytes, in that case there is no payload.
+
+use super::{OpCode, ResultCode, VERSION};
+use crate::types::{Parsable, ParsingError};
+use std::convert::{TryFrom, TryInto};
+use std::net::Ipv4Addr;
+
+/// A correctly formed NAT-PMP `ResponseHeader` containing the `OpCode` of the request,
+/// its `ResultCode` and the epoch of the gateway
+#[derive(PartialEq, Debug)]
+pub struct ResponseHeader {
+    pub opcode: OpCode,
+    pub result: ResultCode,
+    pub epoch: u32,
+}
+
+impl ResponseHeader {
+    /// Size of the NAT-PMP response header (in bytes)
+    pub const SIZE: usize = 8;
+}
+
+/// A correctly formed NAT-PMP mapping response payload, containing the internal port
+/// (copied from the request) and the external port and lifetime assigned by the gateway
+#[derive(PartialEq, Debug)]
+pub struct MappingResponsePayload {
+    pub internal_port: u16,
+    pub external_port: u16,
+    pub lifetime: u32,
+}
+
+impl MappingResponsePayload {
+    /// Size of the NAT-PMP mapping response payload (in bytes)
+    pub const SIZE: usize = 8;
+}
+
+/// An enum containing a NAT-PMP response payload
+#[derive(PartialEq, Debug)]
+pub enum ResponsePayload {
+    /// The external address of the gateway
+    ExternalAddress(Ipv4Addr),
+    Mapping(MappingResponsePayload),
+    /// The gateway didn't send the payload along with an error
+    Empty,
+}
+
+/// A NAT-PMP `Response` containing a `ResponseHeader` and a `ResponsePayload`
+#[derive(PartialEq, Debug)]
+pub struct Response {
+    pub header: ResponseHeader,
+    pub payload: ResponsePayload,
+}
+
+/// A zero-copy type containing a valid NAT-PMP response. It can be obtained via the
+/// `try_from` method (from the `std::TryFrom` trait) from a slice containing
+/// a valid sequence of bytes.
+pub struct ResponseSlice<'a> {
+    slice: &'a [u8],
+}
+
+impl ResponseSlice<'_> {
+    /// Returns the operation code of the request the gateway is responding to
+    pub fn opcode(&self) -> OpCode {
+        // The opcode has already been proven valid
+        (self.slice[1] & 0b_0111_1111).try_into().unwrap()
+    }
+
+    /// Returns the result code of the request
+    pub fn result_code(&self) -> ResultCode {
+        // The result code has already been proven valid
+        u16::from_be_bytes(self.slice[2..4].try_into().unwrap())
+            .try_into()
+            .unwrap()
+    }
+
+    /// Returns the seconds since the start of the epoch of the gateway
+    pub fn epoch(&self) -> u32 {
+        u32::from_be_bytes(self.slice[4..8].try_into().unwrap())
+    }
+
+    /// Returns the payload of the response
+    pub fn payload(&self) -> ResponsePayload {
+        let payload = &self.slice[ResponseHeader::SIZE..];
+        match self.opcode() {
+            _ if payload.is_empty() => ResponsePayload::Empty,
+            OpCode::ExternalAddress => {
+                let octets: [u8; 4] = payload.try_into().unwrap();
+                ResponsePayload::ExternalAddress(octets.into())
+            }
+            OpCode::MapUdp | OpCode::MapTcp => ResponsePayload::Mapping(MappingResponsePayload {
+                internal_port: u16::from_be_bytes(payload[0..2].try_into().unwrap()),
+                external_port: u16::from_be_bytes(payload[2..4].try_into().unwrap()),
+                lifetime: u32::from_be_bytes(payload[4..8].try_into().unwrap()),
+            }),
+        }
+    }
+
+    /// Returns the inner slice
+    pub fn slice(&self) -> &[u8] {
+        self.slice
+    }
+}
+
+impl Parsable for ResponseSlice<'_> {
+    type Parsed = Response;
+
+    fn parse(&self) -> Self::Parsed {
+        Response {
+            header: ResponseHeader {
+                opcode: self.opcode(),
+                result: self.result_code(),
+                epoch: self.epoch(),
+            },
+            payload: self.payload(),
+        }
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for ResponseSlice<'a> {
+    type Error = ParsingError;
+
+    fn try_from(slice: &'a [u8]) -> Result<ResponseSlice<'a>, Self::Error> {
+        if slice.len() < ResponseHeader::SIZE {
+            return Err(ParsingError::InvalidSliceLength(ResponseHeader::SIZE));
+        }
+        if slice[0] != VERSION {
+            return Err(ParsingError::VersionNotSupported(slice[0]));
+        }
+        // The opcode of a response is the one of the request plus 128
+        if slice[1] & 0b_1000_0000 == 0 {
+            return Err(ParsingError::NotAResponse);
+        }
+        let opcode = OpCode::try_from(slice[1] & 0b_0111_1111)?;
+        let result = ResultCode::try_from(u16::from_be_bytes(slice[2..4].try_into().unwrap()))?;
+
+        let size = ResponseHeader::SIZE
+            + match opcode {
+                OpCode::ExternalAddress => 4,
+                OpCode::MapUdp | OpCode::MapTcp => MappingResponsePayload::SIZE,
+            };
+        if slice.len() >= size {
+            Ok(ResponseSlice {
+                slice: &slice[..size],
+            })
+        }
+        // Only an error response can lack the payload
+        else if result != ResultCode::Success {
+            Ok(ResponseSlice {
+                slice: &slice[..ResponseHeader::SIZE],
+            })
+        } else {
+            Err(ParsingError::InvalidSliceLength(size))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn external_address_response() {
+        let data = [0, 128, 0, 0, 0, 0, 0x03, 0xe8, 203, 0, 113, 5];
+        let response = ResponseSlice::try_from(&data[..]).unwrap().parse();
+        assert_eq!(
+            response,
+            Response {
+                header: ResponseHeader {
+                    opcode: OpCode::ExternalAddress,
+                    result: ResultCode::Success,
+                    epoch: 1000,
+                },
+                payload: ResponsePayload::ExternalAddress(Ipv4Addr::new(203, 0, 113, 5)),
+            }
+        );
+    }
+
+    #[test]
+    fn mapping_response() {
+        let data = [
+            0, 130, 0, 0, 0, 0, 0, 10, 0x17, 0x70, 0x1b, 0x58, 0, 0, 0, 120,
+        ];
+        let response = ResponseSlice::try_from(&data[..]).unwrap().parse();
+        assert_eq!(response.header.opcode, OpCode::MapTcp);
+        assert_eq!(
+            response.payload,
+            ResponsePayload::Mapping(MappingResponsePayload {
+                internal_port: 6000,
+                external_port: 7000,
+                lifetime: 120,
+            })
+        );
+    }
+
+    #[test]
+    fn error_response_without_payload() {
+        let data = [0, 129, 0, 2, 0, 0, 0, 10];
+        let response = ResponseSlice::try_from(&data[..]).unwrap();
+        assert_eq!(response.slice().len(), ResponseHeader::SIZE);
+        let response = response.parse();
+        assert_eq!(response.header.result, ResultCode::NotAuthorized);
+        assert_eq!(response.payload, ResponsePayload::Empty);
+
+        // A successful response must have the payload
+        let data = [0, 129, 0, 0, 0, 0, 0, 10];
+        assert!(matches!(
+            ResponseSlice::try_from(&data[..]),
+            Err(ParsingError::InvalidSliceLength(16))
+        ));
+    }
+
+    #[test]
+    fn invalid_responses() {
+        // Unknown opcode
+        let data = [0, 133, 0, 0, 0, 0, 0, 10, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert!(matches!(
+            ResponseSlice::try_from(&data[..]),
+            Err(ParsingError::NotAnOpCode(5))
+        ));
+        // A request
+        let data = [0, 1, 0, 0, 0, 0, 0, 10, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert!(matches!(
+            ResponseSlice::try_from(&data[..]),
+            Err(ParsingError::NotAResponse)
+        ));
+        // A PCP response
+        let data = [2, 129, 0, 0, 0, 0, 0, 10, 0, 0, 0, 0, 0, 0, 0, 0];
+        assert!(matches!(
+            ResponseSlice::try_from(&data[..]),
+            Err(ParsingError::VersionNotSupported(2))
+        ));
+        // Shorter than the header
+        assert!(matches!(
+            ResponseSlice::try_from(&data[..4]),
+            Err(ParsingError::InvalidSliceLength(8))
+        ));
+    }
+}
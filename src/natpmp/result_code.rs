@@ -0,0 +1,51 @@
+use crate::types::{self, ParsingError};
+use std::convert::TryFrom;
+
+/// The `ResultCode` field contained in the NAT-PMP responses (see `ResponseHeader`)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResultCode {
+    /// Success
+    Success = 0,
+    /// The version of the request is not supported
+    UnsuppVersion = 1,
+    /// The gateway supports mapping but the user has turned the feature off
+    NotAuthorized = 2,
+    /// The gateway itself has not obtained a DHCP lease
+    NetworkFailure = 3,
+    /// The gateway cannot create any more mappings at this time
+    OutOfResources = 4,
+    /// Unsupported opcode
+    UnsuppOpcode = 5,
+}
+
+impl From<ResultCode> for types::ResultCode {
+    /// Converts the result code into the PCP one with the same meaning
+    fn from(code: ResultCode) -> Self {
+        match code {
+            ResultCode::Success => Self::Success,
+            ResultCode::UnsuppVersion => Self::UnsuppVersion,
+            ResultCode::NotAuthorized => Self::NotAuthorized,
+            ResultCode::NetworkFailure => Self::NetworkFailure,
+            ResultCode::OutOfResources => Self::NoResources,
+            ResultCode::UnsuppOpcode => Self::UnsuppCode,
+        }
+    }
+}
+
+impl TryFrom<u16> for ResultCode {
+    type Error = ParsingError;
+
+    fn try_from(val: u16) -> Result<Self, Self::Error> {
+        use ResultCode::*;
+
+        match val {
+            0 => Ok(Success),
+            1 => Ok(UnsuppVersion),
+            2 => Ok(NotAuthorized),
+            3 => Ok(NetworkFailure),
+            4 => Ok(OutOfResources),
+            5 => Ok(UnsuppOpcode),
+            n => Err(ParsingError::NotANatPmpResultCode(n)),
+        }
+    }
+}
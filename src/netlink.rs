@@ -0,0 +1,397 @@
+//! The changes of the network configuration, notified by the Linux kernel through
+//! a netlink socket.
+//!
+//! When the host moves to another network, or the DHCP client renews its lease
+//! with a different address, the address used to reach the PCP server changes and
+//! the mappings requested from the old one are lost. The kernel notifies those
+//! changes to the sockets subscribed to the multicast groups of rtnetlink, each
+//! message is made of a `nlmsghdr` followed by:
+//! - an `ifaddrmsg`, for `RTM_NEWADDR` and `RTM_DELADDR`, with the index of the
+//!   interface and the address in the `IFA_LOCAL` (only for IPv4) or `IFA_ADDRESS`
+//!   attribute;
+//! - an `rtmsg`, for `RTM_NEWROUTE`, with the length of the prefix of the
+//!   destination (0 for the default route) and the gateway and output interface in
+//!   the `RTA_GATEWAY` and `RTA_OIF` attributes.
+//!
+//! When a lot of things change at once the kernel might run out of space for the
+//! messages, which are lost (the socket fails with `ENOBUFS`): that's notified as
+//! `Change::Overrun`, after which the whole configuration has to be looked up again.
+//!
+//! The messages are parsed by `parse`, which only needs the bytes received, while
+//! the socket is opened by the client when it's built with
+//! `ClientBuilder::watch_network`. This module is available only on Linux with
+//! the `netlink` feature.
+
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use std::convert::{TryFrom, TryInto};
+use std::fs::File;
+use std::io::{self, Read};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::io::{FromRawFd, IntoRawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Type of the messages that notify a new address
+pub const RTM_NEWADDR: u16 = 20;
+/// Type of the messages that notify a removed address
+pub const RTM_DELADDR: u16 = 21;
+/// Type of the messages that notify a new route
+pub const RTM_NEWROUTE: u16 = 24;
+
+/// Multicast group of the IPv4 address changes
+const RTMGRP_IPV4_IFADDR: u32 = 0x10;
+/// Multicast group of the IPv4 route changes
+const RTMGRP_IPV4_ROUTE: u32 = 0x40;
+/// Multicast group of the IPv6 address changes
+const RTMGRP_IPV6_IFADDR: u32 = 0x100;
+/// Multicast group of the IPv6 route changes
+const RTMGRP_IPV6_ROUTE: u32 = 0x400;
+
+/// Interval at which the thread watching the network checks if it has to stop
+const WATCH_POLL: Duration = Duration::from_millis(250);
+
+/// Size of the header of a netlink message (`nlmsghdr`)
+const HEADER_SIZE: usize = 16;
+/// Size of the header of an address message (`ifaddrmsg`)
+const IFADDRMSG_SIZE: usize = 8;
+/// Size of the header of a route message (`rtmsg`)
+const RTMSG_SIZE: usize = 12;
+
+/// Attribute of the address of the interface (the one of the peer, for point-to-point links)
+const IFA_ADDRESS: u16 = 1;
+/// Attribute of the local address of the interface
+const IFA_LOCAL: u16 = 2;
+/// Attribute of the output interface of a route
+const RTA_OIF: u16 = 4;
+/// Attribute of the gateway of a route
+const RTA_GATEWAY: u16 = 5;
+
+/// A change of the network configuration
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Change {
+    /// An address has been added to the interface with the specified index
+    NewAddress(u32, IpAddr),
+    /// An address has been removed from the interface with the specified index
+    DelAddress(u32, IpAddr),
+    /// A route has been added
+    NewRoute {
+        /// Length of the prefix of the destination, 0 for the default route
+        prefix: u8,
+        /// Address of the gateway, if any
+        gateway: Option<IpAddr>,
+        /// Index of the output interface, if any
+        interface: Option<u32>,
+    },
+    /// Some notifications have been lost, as the kernel ran out of space for them (which happens
+    /// when a lot of things change at once), the whole configuration has to be looked up again
+    Overrun,
+}
+
+/// Rounds the length up to the alignment of the netlink messages and attributes
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+/// Reads a native endian `u16` at the specified offset
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    Some(u16::from_ne_bytes(
+        data.get(offset..offset + 2)?.try_into().ok()?,
+    ))
+}
+
+/// Reads a native endian `u32` at the specified offset
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_ne_bytes(
+        data.get(offset..offset + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Returns the attributes (`rtattr`) that follow the header of a message, as type and data
+fn attributes(mut data: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        let len = u16_at(data, 0)? as usize;
+        let kind = u16_at(data, 2)?;
+        let value = data.get(4..len)?;
+        data = data.get(align(len)..).unwrap_or_default();
+        Some((kind, value))
+    })
+}
+
+/// Converts the data of an attribute into an address of the specified family
+fn address(family: u8, data: &[u8]) -> Option<IpAddr> {
+    match family as i32 {
+        libc::AF_INET => Some(Ipv4Addr::from(<[u8; 4]>::try_from(data).ok()?).into()),
+        libc::AF_INET6 => Some(Ipv6Addr::from(<[u8; 16]>::try_from(data).ok()?).into()),
+        _ => None,
+    }
+}
+
+/// Parses an address message (the part after the `nlmsghdr`)
+fn parse_address(kind: u16, payload: &[u8]) -> Option<Change> {
+    let family = *payload.first()?;
+    let index = u32_at(payload, 4)?;
+    let mut addr = None;
+    for (attribute, data) in attributes(payload.get(IFADDRMSG_SIZE..)?) {
+        match attribute {
+            IFA_LOCAL => addr = address(family, data),
+            IFA_ADDRESS if addr.is_none() => addr = address(family, data),
+            _ => (),
+        }
+    }
+    match kind {
+        RTM_NEWADDR => Some(Change::NewAddress(index, addr?)),
+        _ => Some(Change::DelAddress(index, addr?)),
+    }
+}
+
+/// Parses a route message (the part after the `nlmsghdr`)
+fn parse_route(payload: &[u8]) -> Option<Change> {
+    let family = *payload.first()?;
+    let prefix = *payload.get(1)?;
+    let (mut gateway, mut interface) = (None, None);
+    for (attribute, data) in attributes(payload.get(RTMSG_SIZE..)?) {
+        match attribute {
+            RTA_GATEWAY => gateway = address(family, data),
+            RTA_OIF => interface = u32_at(data, 0),
+            _ => (),
+        }
+    }
+    Some(Change::NewRoute {
+        prefix,
+        gateway,
+        interface,
+    })
+}
+
+/// Parses the netlink messages received in a single datagram, returning the changes they
+/// notify. The other messages (and the malformed ones) are skipped
+pub fn parse(mut data: &[u8]) -> Vec<Change> {
+    let mut changes = Vec::new();
+    while data.len() >= HEADER_SIZE {
+        let len = u32_at(data, 0).unwrap_or(0) as usize;
+        let kind = u16_at(data, 4).unwrap_or(0);
+        let payload = match data.get(HEADER_SIZE..len) {
+            Some(payload) => payload,
+            None => break,
+        };
+        let change = match kind {
+            RTM_NEWADDR | RTM_DELADDR => parse_address(kind, payload),
+            RTM_NEWROUTE => parse_route(payload),
+            _ => None,
+        };
+        changes.extend(change);
+        data = data.get(align(len)..).unwrap_or_default();
+    }
+    changes
+}
+
+/// Opens a netlink socket subscribed to the address and route changes of the specified family
+fn subscribe(v6: bool) -> io::Result<File> {
+    let socket = Socket::new(
+        Domain::from(libc::AF_NETLINK),
+        Type::RAW,
+        Some(Protocol::from(libc::NETLINK_ROUTE)),
+    )?;
+    let groups = match v6 {
+        false => RTMGRP_IPV4_IFADDR | RTMGRP_IPV4_ROUTE,
+        true => RTMGRP_IPV6_IFADDR | RTMGRP_IPV6_ROUTE,
+    };
+    // SAFETY: the storage is big enough for a `sockaddr_nl`, which is all zeros but its
+    // family and groups
+    let (_, addr) = unsafe {
+        SockAddr::try_init(|storage, len| {
+            let nl = storage.cast::<libc::sockaddr_nl>();
+            (*nl).nl_family = libc::AF_NETLINK as libc::sa_family_t;
+            (*nl).nl_groups = groups;
+            *len = std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t;
+            Ok(())
+        })
+    }?;
+    socket.bind(&addr)?;
+    socket.set_read_timeout(Some(WATCH_POLL))?;
+    // SAFETY: the descriptor is owned by the socket, which gives it up
+    Ok(unsafe { File::from_raw_fd(socket.into_raw_fd()) })
+}
+
+/// The thread started by `watch`, which is stopped when this is dropped
+pub(crate) struct Watcher {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Watcher {
+    /// Stops the thread and waits for it to end
+    pub fn join(mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Starts a thread that calls `notify` on each change of the addresses and routes of the
+/// specified family, until it returns `false` or the returned `Watcher` stops it. If the socket
+/// fails the error is notified and the thread ends
+pub(crate) fn watch<F>(v6: bool, mut notify: F) -> io::Result<Watcher>
+where
+    F: FnMut(io::Result<Change>) -> bool + Send + 'static,
+{
+    let mut socket = subscribe(v6)?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = Arc::clone(&stop);
+    let thread = std::thread::spawn(move || {
+        let mut buf = vec![0; 8192];
+        // The reads time out so that the flag is checked every now and then
+        while !stopped.load(Ordering::Relaxed) {
+            let changes = match socket.read(&mut buf) {
+                Ok(bytes) => parse(&buf[..bytes]),
+                Err(err) if is_timeout(&err) => continue,
+                Err(err) if err.raw_os_error() == Some(libc::ENOBUFS) => vec![Change::Overrun],
+                Err(err) => {
+                    notify(Err(err));
+                    return;
+                }
+            };
+            for change in changes {
+                if !notify(Ok(change)) {
+                    return;
+                }
+            }
+        }
+    });
+    Ok(Watcher {
+        stop,
+        thread: Some(thread),
+    })
+}
+
+/// Tells if the error is caused by the timeout of a read
+fn is_timeout(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+    )
+}
+
+// The messages are in the byte order of the host, these are laid out as the kernel sends them
+// on a little endian machine
+#[cfg(all(test, target_endian = "little"))]
+mod tests {
+    use super::*;
+
+    /// `ip addr add 192.168.1.101/24 dev eth0` (interface 2)
+    const NEWADDR_V4: [u8; 72] = [
+        72, 0, 0, 0, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // nlmsghdr
+        2, 24, 0x80, 0, 2, 0, 0, 0, // ifaddrmsg
+        8, 0, 1, 0, 192, 168, 1, 101, // IFA_ADDRESS
+        8, 0, 2, 0, 192, 168, 1, 101, // IFA_LOCAL
+        9, 0, 3, 0, b'e', b't', b'h', b'0', 0, 0, 0, 0, // IFA_LABEL (padded)
+        20, 0, 6, 0, // IFA_CACHEINFO
+        255, 255, 255, 255, 255, 255, 255, 255, // preferred and valid lifetimes
+        0, 0, 0, 0, 0, 0, 0, 0, // timestamps
+    ];
+
+    /// `ip addr del 2001:db8::5/64 dev eth1` (interface 3)
+    const DELADDR_V6: [u8; 44] = [
+        44, 0, 0, 0, 21, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // nlmsghdr
+        10, 64, 0, 0, 3, 0, 0, 0, // ifaddrmsg
+        20, 0, 1, 0, // IFA_ADDRESS
+        0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 5, // 2001:db8::5
+    ];
+
+    /// `ip route add default via 192.168.1.1 dev eth0`
+    const NEWROUTE_V4: [u8; 52] = [
+        52, 0, 0, 0, 24, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // nlmsghdr
+        2, 0, 0, 0, 254, 4, 0, 1, 0, 0, 0, 0, // rtmsg
+        8, 0, 15, 0, 254, 0, 0, 0, // RTA_TABLE
+        8, 0, 5, 0, 192, 168, 1, 1, // RTA_GATEWAY
+        8, 0, 4, 0, 2, 0, 0, 0, // RTA_OIF
+    ];
+
+    #[test]
+    fn new_address() {
+        let addr = Ipv4Addr::new(192, 168, 1, 101).into();
+        assert_eq!(parse(&NEWADDR_V4), vec![Change::NewAddress(2, addr)]);
+    }
+
+    #[test]
+    fn deleted_address() {
+        let addr = "2001:db8::5".parse().unwrap();
+        assert_eq!(parse(&DELADDR_V6), vec![Change::DelAddress(3, addr)]);
+    }
+
+    #[test]
+    fn new_route() {
+        let change = Change::NewRoute {
+            prefix: 0,
+            gateway: Some(Ipv4Addr::new(192, 168, 1, 1).into()),
+            interface: Some(2),
+        };
+        assert_eq!(parse(&NEWROUTE_V4), vec![change]);
+    }
+
+    #[test]
+    fn more_messages_in_a_datagram() {
+        let mut data = NEWROUTE_V4.to_vec();
+        data.extend_from_slice(&DELADDR_V6);
+        let changes = parse(&data);
+        assert_eq!(changes.len(), 2);
+        assert!(matches!(changes[0], Change::NewRoute { .. }));
+        assert!(matches!(changes[1], Change::DelAddress(3, _)));
+    }
+
+    #[test]
+    fn other_messages_are_skipped() {
+        // RTM_NEWLINK, followed by an address
+        let mut data = vec![20, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        data.extend_from_slice(&NEWADDR_V4);
+        assert_eq!(parse(&data).len(), 1);
+    }
+
+    #[test]
+    fn misaligned_attribute() {
+        let data = [
+            40, 0, 0, 0, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // nlmsghdr
+            2, 24, 0, 0, 2, 0, 0, 0, // ifaddrmsg
+            6, 0, 3, 0, b'e', 0, 0, 0, // IFA_LABEL, 6 bytes long but padded to 8
+            8, 0, 2, 0, 10, 0, 0, 7, // IFA_LOCAL
+        ];
+        let addr = Ipv4Addr::new(10, 0, 0, 7).into();
+        assert_eq!(parse(&data), vec![Change::NewAddress(2, addr)]);
+    }
+
+    #[test]
+    fn truncated_attribute() {
+        // The IFA_LOCAL attribute claims to be longer than the message
+        let data = [
+            30, 0, 0, 0, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // nlmsghdr
+            2, 24, 0, 0, 2, 0, 0, 0, // ifaddrmsg
+            8, 0, 2, 0, 10, 0, // IFA_LOCAL
+        ];
+        assert_eq!(parse(&data), vec![]);
+        // An address of the wrong size
+        let data = [
+            32, 0, 0, 0, 20, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, // nlmsghdr
+            2, 24, 0, 0, 2, 0, 0, 0, // ifaddrmsg
+            7, 0, 2, 0, 10, 0, 0, 0, // IFA_LOCAL
+        ];
+        assert_eq!(parse(&data), vec![]);
+    }
+
+    #[test]
+    fn truncated_message() {
+        // The second message is longer than the datagram, the first one is still parsed
+        let mut data = NEWADDR_V4.to_vec();
+        data.extend_from_slice(&NEWROUTE_V4[..40]);
+        assert_eq!(parse(&data).len(), 1);
+        assert_eq!(parse(&NEWROUTE_V4[..10]), vec![]);
+    }
+}
@@ -0,0 +1,198 @@
+//! The policy that tells a `PcpSession` when to retransmit the requests and when
+//! to renew the mappings.
+//!
+//! The RFC defines the retransmission of a request with four values: the _Initial
+//! Retransmission Time_ (IRT), the _Maximum Retransmission Time_ (MRT), the _Maximum
+//! Retransmission Count_ (MRC) and the _Maximum Retransmission Duration_ (MRD).
+//! Each retransmission time (RT) doubles the previous one, up to the MRT, and each
+//! one has a random factor (from 0.9 to 1.1) so that the clients don't synchronize:
+//!
+//! ```text
+//! RT = (1 + RAND) * IRT
+//! RT = (1 + RAND) * MIN (2 * RTprev, MRT)
+//! ```
+//!
+//! Once a mapping is running it has to be renewed before its lifetime ends: the
+//! first renewal is sent between 1/2 and 5/8 of the lifetime, and if the server
+//! doesn't respond the next ones between 3/4 and 3/4 + 1/16, 7/8 and 7/8 + 1/32 and
+//! so on, as long as they are at least four seconds apart.
+//!
+//! `RfcPolicy` implements exactly that (with configurable values), other behaviours
+//! can be obtained by implementing `RetryPolicy`.
+
+use rand::{Rng, RngCore};
+use std::time::Duration;
+
+/// Minimum time between two renewals of a mapping
+const MIN_RENEWAL_GAP: f32 = 4.0;
+
+/// Decides the timing of the retransmissions of the requests and of the renewals of the
+/// mappings
+pub trait RetryPolicy: Send {
+    /// Returns the time to wait before the first retransmission of a request
+    fn initial_rt(&self, rng: &mut dyn RngCore) -> Duration;
+
+    /// Returns the time to wait before the next retransmission of a request, given the previous
+    /// retransmission time
+    fn next_rt(&self, rng: &mut dyn RngCore, prev: Duration) -> Duration;
+
+    /// Returns the maximum number of times a request is sent, `None` if there is no limit
+    fn max_count(&self) -> Option<usize>;
+
+    /// Returns the maximum amount of time a request is retransmitted for, `None` if there is no
+    /// limit
+    fn max_duration(&self) -> Option<Duration>;
+
+    /// Returns when (counting from the moment the lifetime was assigned) the `attempt`-th
+    /// renewal of a mapping with the specified lifetime has to be sent, or `None` if the mapping
+    /// has to be left expiring
+    fn renewal(
+        &self,
+        rng: &mut dyn RngCore,
+        lifetime: Duration,
+        attempt: usize,
+    ) -> Option<Duration>;
+}
+
+/// The retransmission and renewal policy described in the RFC
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct RfcPolicy {
+    /// Initial Retransmission Time
+    pub irt: Duration,
+    /// Maximum Retransmission Time
+    pub mrt: Duration,
+    /// Maximum Retransmission Count (0 = infinite)
+    pub mrc: usize,
+    /// Maximum Retransmission Duration (0 = infinite)
+    pub mrd: Duration,
+}
+
+impl Default for RfcPolicy {
+    /// Returns the policy with the default values of the RFC
+    fn default() -> Self {
+        Self {
+            irt: Duration::from_secs(3),
+            mrt: Duration::from_secs(1024),
+            mrc: 0,
+            mrd: Duration::from_secs(0),
+        }
+    }
+}
+
+/// Generates the 1 + RAND factor used in the IRT and RT functions
+fn one_plus_rand(rng: &mut dyn RngCore) -> f32 {
+    // RAND sould be a value between -0.1 and 0.1, but by subtracting it from one the range
+    // becomes from 0.9 to 1.1, thus I can generate a number between 0 as 0.2 and add it to 0.9
+    0.9 + rng.gen::<f32>() * 0.2
+}
+
+impl RetryPolicy for RfcPolicy {
+    fn initial_rt(&self, rng: &mut dyn RngCore) -> Duration {
+        self.irt.mul_f32(one_plus_rand(rng))
+    }
+
+    fn next_rt(&self, rng: &mut dyn RngCore, prev: Duration) -> Duration {
+        self.mrt.min(prev * 2).mul_f32(one_plus_rand(rng))
+    }
+
+    fn max_count(&self) -> Option<usize> {
+        Some(self.mrc).filter(|&mrc| mrc != 0)
+    }
+
+    fn max_duration(&self) -> Option<Duration> {
+        Some(self.mrd).filter(|mrd| !mrd.is_zero())
+    }
+
+    fn renewal(
+        &self,
+        rng: &mut dyn RngCore,
+        lifetime: Duration,
+        attempt: usize,
+    ) -> Option<Duration> {
+        // The n-th renewal is sent between 1 - 1/2^(n+1) and that plus 1/2^(n+3) of the lifetime
+        let step = 0.5f32.powi(attempt as i32 + 1);
+        // Space between the latest time of the previous renewal (or the assignment of the
+        // lifetime) and the earliest time of this one
+        let gap = match attempt {
+            0 => step,
+            _ => step / 2.0,
+        };
+        if lifetime.as_secs_f32() * gap < MIN_RENEWAL_GAP {
+            return None;
+        }
+        Some(lifetime.mul_f32(1.0 - step + rng.gen::<f32>() * step / 4.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn rng() -> StdRng {
+        StdRng::seed_from_u64(6887)
+    }
+
+    /// Tells if the duration is within 10% of the expected one
+    fn around(duration: Duration, expected: Duration) -> bool {
+        duration >= expected.mul_f32(0.9) && duration <= expected.mul_f32(1.1)
+    }
+
+    #[test]
+    fn retransmission_times() {
+        let (mut rng, policy) = (rng(), RfcPolicy::default());
+        for _ in 0..100 {
+            assert!(around(policy.initial_rt(&mut rng), policy.irt));
+        }
+        // Doubled until the MRT is reached
+        let mut rt = policy.irt;
+        for _ in 0..20 {
+            let next = policy.next_rt(&mut rng, rt);
+            assert!(around(next, policy.mrt.min(rt * 2)));
+            rt = next;
+        }
+        assert!(around(rt, policy.mrt));
+    }
+
+    #[test]
+    fn retransmission_limits() {
+        let policy = RfcPolicy::default();
+        assert_eq!(policy.max_count(), None);
+        assert_eq!(policy.max_duration(), None);
+        let policy = RfcPolicy {
+            mrc: 5,
+            mrd: Duration::from_secs(30),
+            ..policy
+        };
+        assert_eq!(policy.max_count(), Some(5));
+        assert_eq!(policy.max_duration(), Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn renewals() {
+        let (mut rng, policy) = (rng(), RfcPolicy::default());
+        let lifetime = Duration::from_secs(120);
+        // Between 1/2 and 5/8, 3/4 and 3/4 + 1/16, 7/8 and 7/8 + 1/32
+        let windows = [(0.5, 0.625), (0.75, 0.8125), (0.875, 0.90625)];
+        for _ in 0..100 {
+            for (attempt, &(from, to)) in windows.iter().enumerate() {
+                let renewal = policy.renewal(&mut rng, lifetime, attempt).unwrap();
+                assert!(renewal >= lifetime.mul_f32(from));
+                assert!(renewal <= lifetime.mul_f32(to));
+            }
+        }
+        // The next one would be less than 4 seconds after the previous one
+        assert_eq!(policy.renewal(&mut rng, lifetime, 3), None);
+    }
+
+    #[test]
+    fn no_renewal_of_short_lifetimes() {
+        let (mut rng, policy) = (rng(), RfcPolicy::default());
+        assert_eq!(policy.renewal(&mut rng, Duration::from_secs(6), 0), None);
+        assert!(policy
+            .renewal(&mut rng, Duration::from_secs(8), 0)
+            .is_some());
+        assert_eq!(policy.renewal(&mut rng, Duration::from_secs(8), 1), None);
+    }
+}
@@ -0,0 +1,2043 @@
+//! The `PcpSession` is the heart of the system: it implements the actual protocol
+//! without doing any I/O on its own.
+//!
+//! # Sans-IO
+//!
+//! A `PcpSession` never touches a socket, a channel or a thread, it just reacts
+//! to what is fed to it and queues what has to be done next:
+//! - the datagrams received from the PCP server are passed to `handle_datagram`,
+//!   along with the index of the server they come from (see below);
+//! - the user commands are submitted via `request_inbound`, `request_outbound`,
+//!   `renew`, `revoke` and `remove`;
+//! - once the instant returned by `poll_timeout` is reached, `handle_timeout`
+//!   has to be called.
+//!
+//! After each of those calls the datagrams that have to be sent to the server can
+//! be taken with `poll_transmit`, each one with the index of the server it's for,
+//! and the notifications for the application with
+//! `poll_event`. Every method that depends on time takes the current `Instant`,
+//! so the protocol can be driven deterministically (the threaded `Client` is just
+//! a driver built on top of this).
+//!
+//! # Internal Workings
+//!
+//! When a mapping is request the session constructs a new `MappingState` containing
+//! the informations of that mapping and stores it in a `Slab` (see the `slab` module),
+//! while also queueing the request for the server. The slots left empty by dropped
+//! mappings are reused, but each mapping is identified by a `MappingId` that also
+//! contains the generation of its slot, so the id of a dropped mapping never refers
+//! to the one that took its place.
+//!
+//! Each mapping also has its own nonce, which is the proof that the mapping belongs
+//! to this client: unless it's specified by the user, it's made of 96 bits drawn from
+//! the random number generator of the operating system. The nonces are also the keys
+//! of a lookup table used to find the mapping a response refers to; the identity of
+//! a mapping is still its `MappingId`, so more mappings may share a nonce.
+//!
+//! The nonces are lost when the process ends, so the active mappings can be saved
+//! with `stored` and requested again on the next run (see the `store` module):
+//! the mapping rebuilt from a `StoredMapping` has the same nonce, thus the server
+//! refreshes the existing one instead of creating another.
+//!
+//! A response is attributed to a mapping only if it matches its request as described
+//! in the RFC, the ones that don't match any request are reported with
+//! `SessionEvent::Unmatched`. For this reason a dropped mapping isn't forgotten right
+//! away, but only once the server responds to its deletion (which is notified with
+//! `SessionEvent::Deleted` when it succeeds) or the deletion is given up on.
+//!
+//! # Deletion
+//!
+//! A mapping is deleted, when it's revoked or dropped, by sending its request with a
+//! lifetime of 0. The request is retransmitted like any other, following the
+//! `RetryPolicy`, while the mapping is `Deleting` (or `Dropped`): only the response
+//! of the server with a lifetime of 0 confirms the deletion, and the mapping becomes
+//! `Revoked`, while an error response (e.g. `NotAuthorized`, when the mapping
+//! belongs to someone else) leaves it in the `Error` state. As the mapping ends on
+//! the server with its lifetime anyway, the deletion isn't retransmitted after that
+//! and the mapping becomes `Expired`.
+//!
+//! The server may also send responses on its own, when the external address of the
+//! mappings changes: they are attributed like any other response, and the session
+//! keeps the last external address assigned to each mapping so that the change is
+//! notified with `Alert::ExternalAddressChanged`. The external address and port are
+//! notified with `Alert::Assigned` for every kind of mapping, the outbound ones also
+//! get `Alert::PeerAssigned` with the whole response, which contains the remote
+//! address and port echoed by the server.
+//!
+//! Another thing is done while reqesting a new maping, and that is to start a
+//! timer (see the `timer` module) that waits for a specific amount of time (defined by the RFC) after which
+//! the request is sended again and another timer is started with a longer
+//! duration. This process repeats until the server responds or a maximum number of
+//! times (or amount of time) is reached, after which the request is considered to
+//! be `Expired`. All those values are decided by the `RetryPolicy` of the session
+//! (see the `retry` module), by default the one of the RFC. Before
+//! becoming `Running` once the server responds, or `Expired` when it doesn't, the
+//! mapping is in the `Starting` state.
+//!
+//! When the mapping is finally `Running` another timer is started that lasts for
+//! it's lifetime. The duration of this timer depends on the requested amount of
+//! times it has to leave, which can be finate (`Repeat(n)` or `Once`) or endelss
+//! (`KeepAlive`). In the latter two cases the time to wait has to be smaller than
+//! the actual lifetime has the renewal is not immidiate: the renewals are sent at
+//! the instants decided by the policy, counted from when the server assigned the
+//! lifetime, until one of them succeeds or the mapping expires.
+//!
+//! # The Epoch and Recovery
+//!
+//! Every time a response is received a check is made on the server epoch to verify
+//! that the server didn't lose it's state (see the `epoch` module), the epoch
+//! received becomes the reference for the next check. In the case that the check
+//! fails the application is notified with `SessionEvent::ServerReset` and the
+//! state of the server has to be updated, thus all the currently active mappings
+//! have to be resent. The correct thing to do might be to send the requests with
+//! the lifetime decreased to the amount left before the error, but in reality it
+//! can't be determined the exact moment of failure thus they are sent again with
+//! their full lifetime.
+//!
+//! The recovery procedure is actuated, also, when an _unsolicited announce response_
+//! arrives, which means that the server had some problems and lost it's state. As
+//! the announce might be multicast to all the hosts of the network, and anyone could
+//! have sent it, the client waits a random delay (up to 5 seconds) and then verifies
+//! it by sending an announce request to the server: only if the epoch of the
+//! response is invalid the mappings are sent again.
+//!
+//! The mappings aren't all sent at once, their requests are paced so that the
+//! server (which is likely receiving the requests of all the other hosts) isn't
+//! flooded.
+//!
+//! # Announce Requests
+//!
+//! The client can also send announce requests on its own, to know if the server
+//! is reachable, which version of the protocol it speaks and its epoch. The
+//! request is retransmitted a few times, and once the server responds (or it
+//! doesn't) a `SessionEvent::Announced` is produced. The response is validated like
+//! all the others, so when the health-check is enabled, and there are no active
+//! mappings whose responses would reveal it, a reboot of the server is still
+//! detected.
+//!
+//! # Server Selection
+//!
+//! The PCP server might be reachable at more than one address (or there might be
+//! more servers), and RFC 7488 says to try them in order of preference. Other
+//! than the first one, given when the session is created, the candidates are
+//! added with `add_server` along with the address of the client used to reach
+//! each one (which is the one written in the requests), and they are identified by
+//! their index. The requests are sent to the first candidate until a few of them
+//! have been retransmitted without any response, then to the next one, and so on
+//! (starting again from the first one after the last).
+//!
+//! As soon as one of them responds it's selected, and the requests are sent only
+//! to it from then on: the mappings live on that server, so the datagrams coming
+//! from the others are ignored.
+//!
+//! The address of the client used to reach a server might change too, when the
+//! host moves to another network or gets another address: `set_address` rewrites
+//! the requests with the new one and, if it's the server in use, all the active
+//! mappings are sent again (in the same way as when the server loses its state)
+//! and their handles are notified with `Alert::ClientAddressChanged`.
+//!
+//! # Version Negotiation
+//!
+//! The requests are sent with the highest version of PCP supported (2). A server
+//! that doesn't support it answers with `UnsuppVersion` and the highest version it
+//! supports, then, if it's a lower one (the version 1 is still used by some old
+//! gateways), the session remembers it and sends all the active mappings again with
+//! that version. If the server only speaks later versions (or none) the active
+//! mappings end with the `UnsuppVersion` error. The version is remembered separately
+//! for each candidate server, the others still start from the highest one. When the
+//! server is restarted (an announce is received or the epoch is invalid) it might
+//! have been upgraded, so the highest version is tried again.
+//!
+//! # NAT-PMP Fallback
+//!
+//! A gateway that only speaks NAT-PMP answers to the PCP requests with a NAT-PMP
+//! packet (see the `natpmp` module, the version 0 of the protocol), then, with IPv4,
+//! the session switches to NAT-PMP and requests all the active mappings again,
+//! translating them into NAT-PMP requests. Only inbound UDP or TCP mappings without
+//! options can be translated, the others end up in the `Error` state.
+//!
+//! NAT-PMP responses don't contain the external address, so it's requested
+//! separately along with the mappings until the gateway sends it, the `Assigned`
+//! alert of a mapping is delayed until then.
+
+use super::epoch::EpochTracker;
+use super::handle::RequestType;
+use super::map::{InboundMap, Mapping, OutboundMap};
+use super::natpmp;
+use super::retry::{RetryPolicy, RfcPolicy};
+use super::slab::{MappingId, Slab};
+use super::state::{Alert, MappingState, PeerAssignment, State};
+use super::store::StoredMapping;
+use super::timer::Scheduler;
+use super::IpAddress;
+use crate::types::headers::ResponseHeaderSlice;
+use crate::types::payloads::{PeerResponsePayload, RequestPayload, ResponsePayload};
+use crate::types::{
+    OpCode, OptionCode, PacketOption, Parsable, ParsingError, ProtocolNumber, RequestPacket,
+    ResponsePacket, ResponsePacketSlice, ResultCode,
+};
+use rand::rngs::{OsRng, StdRng};
+use rand::{Rng, RngCore, SeedableRng};
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant};
+
+/// Highest version of PCP supported
+const VERSION: u8 = 2;
+
+/// Maximum number of times an announce request is sent before giving up on the server
+const ANNOUNCE_MRC: usize = 4;
+/// Maximum delay (in milliseconds) before verifying an unsolicited announce
+const ANNOUNCE_DELAY: u64 = 5000;
+/// Interval between the requests of the mappings sent again after the server lost its state
+const RESEND_PACE: Duration = Duration::from_millis(50);
+/// Number of retransmissions without any response after which the next server is tried
+const FAILOVER_COUNT: usize = 3;
+
+/// A notification produced by a `PcpSession` for the application
+#[derive(Debug)]
+pub enum SessionEvent {
+    /// The state of the mapping (1st) changed to a new value (2nd)
+    StateChange(MappingId, State),
+    /// An alert (2nd) regarding the mapping (1st)
+    Alert(MappingId, Alert),
+    /// The server confirmed the deletion of the mapping, which has been forgotten
+    Deleted(MappingId),
+    /// A MAP or PEER response that doesn't match any of the requests
+    Unmatched(ResponsePacket),
+    /// The server lost its state (it has been restarted), the mappings are being requested again
+    ServerReset,
+    /// The server responded to the announce request (see `PcpSession::announce`), `None` if it
+    /// never did
+    Announced(Option<ServerStatus>),
+}
+
+/// The status of the server, as reported by the response to an announce request
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ServerStatus {
+    /// Version of the protocol spoken by the server, 0 means that it only speaks NAT-PMP
+    pub version: u8,
+    /// Epoch time of the server
+    pub epoch: u32,
+    /// Time elapsed between the last transmission of the request and the response
+    pub rtt: Duration,
+    /// Result code of the response
+    pub result: ResultCode,
+}
+
+/// The timers of a `PcpSession`
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord, Debug)]
+enum Timer {
+    /// Retransmission, renewal or expiration of a mapping
+    Mapping(MappingId),
+    /// Retransmission of the announce request
+    Announce,
+    /// Periodic announce request sent while there are no active mappings
+    HealthCheck,
+    /// Verification of an unsolicited announce
+    Verify,
+    /// Request of the next mapping to send again after the server lost its state
+    Resend,
+}
+
+/// An announce request waiting for its response
+#[derive(Debug)]
+struct Probe {
+    /// When the request was last sent
+    sent: Instant,
+    /// Last retransmission time used
+    rt: Duration,
+    /// Number of times the request has been sent
+    count: usize,
+}
+
+/// A candidate server
+#[derive(Debug)]
+struct Server<Ip> {
+    /// Address of the client used to reach it
+    source: Ip,
+    /// Version of the protocol used with it, 0 means that it only speaks NAT-PMP
+    version: u8,
+}
+
+impl<Ip> Server<Ip> {
+    /// Creates a server reached from the specified address, the highest version is tried first
+    fn new(source: Ip) -> Self {
+        Self {
+            source,
+            version: VERSION,
+        }
+    }
+}
+
+/// The PCP protocol (client-side) implemented as a pure state machine.
+///
+/// A `PcpSession` works only with IPv4 addresses or only with IPv6 addresses
+///
+/// # Examples
+///
+/// Driving a session by hand:
+/**
+
+    use std::net::{Ipv4Addr, UdpSocket};
+    use std::time::Instant;
+
+    let mut session = PcpSession::new(Ipv4Addr::new(192, 168, 1, 101));
+    let socket = UdpSocket::bind("192.168.1.101:0").unwrap();
+    socket.connect("192.168.1.1:5351").unwrap();
+
+    let map = InboundMap::new(6000, 120).protocol(ProtocolNumber::Tcp);
+    let id = session.request_inbound(map, RequestType::KeepAlive, Instant::now());
+
+    // There is only one server, the one with index 0
+    while let Some((_, datagram)) = session.poll_transmit() {
+        socket.send(&datagram).unwrap();
+    }
+    // Wait for a datagram or until session.poll_timeout()...
+*/
+pub struct PcpSession<Ip: IpAddress> {
+    /// The candidate servers
+    servers: Vec<Server<Ip>>,
+    /// Index of the server the requests are sent to
+    server: usize,
+    /// Whether the server has responded, after which it's never changed
+    selected: bool,
+    /// Number of retransmissions since the server last responded
+    unanswered: usize,
+    /// Data of each mapping
+    mappings: Slab<MappingState>,
+    /// Ids of the mappings that use each nonce
+    nonces: HashMap<[u8; 12], Vec<MappingId>>,
+    /// Timers of the mappings, identified by the id of the mapping
+    timers: Scheduler<Timer>,
+    /// RNG used for generating RTs and jitters (the nonces come from the OS)
+    rng: StdRng,
+    /// Timing of the retransmissions and of the renewals
+    policy: Box<dyn RetryPolicy>,
+    /// Epoch of the server
+    epoch: EpochTracker,
+    /// External address of the NAT-PMP gateway, once it's known
+    external: Option<IpAddr>,
+    /// Announce request waiting for a response, if any
+    probe: Option<Probe>,
+    /// Interval between the health-check announce requests, if enabled
+    health_check: Option<Duration>,
+    /// Mappings waiting to be sent again after the server lost its state
+    resend: VecDeque<MappingId>,
+    /// Datagrams waiting to be sent, with the index of the server they are for
+    transmits: VecDeque<(usize, Vec<u8>)>,
+    /// Notifications waiting to be taken by the application
+    events: VecDeque<SessionEvent>,
+}
+
+impl<Ip: IpAddress> PcpSession<Ip> {
+    /// Creates a new `PcpSession` for the client with the specified address
+    pub fn new(addr: Ip) -> Self {
+        Self::with_policy(addr, Box::new(RfcPolicy::default()))
+    }
+
+    /// Creates a session, where the client has the specified address, that retransmits the
+    /// requests and renews the mappings following the specified policy
+    pub fn with_policy(addr: Ip, policy: Box<dyn RetryPolicy>) -> Self {
+        Self {
+            servers: vec![Server::new(addr)],
+            server: 0,
+            selected: false,
+            unanswered: 0,
+            policy,
+            mappings: Slab::new(),
+            nonces: HashMap::new(),
+            timers: Scheduler::new(),
+            rng: StdRng::from_entropy(),
+            epoch: EpochTracker::new(),
+            external: None,
+            probe: None,
+            health_check: None,
+            resend: VecDeque::new(),
+            transmits: VecDeque::new(),
+            events: VecDeque::new(),
+        }
+    }
+
+    /// Adds a server to try when the previous ones don't respond, `addr` is the address of the
+    /// client used to reach it. The servers are identified by the order in which they are added,
+    /// the one of the client passed to `new` has the index 0
+    pub fn add_server(&mut self, addr: Ip) -> usize {
+        self.servers.push(Server::new(addr));
+        self.servers.len() - 1
+    }
+
+    /// Returns the index of the server the requests are sent to
+    pub fn server(&self) -> usize {
+        self.server
+    }
+
+    /// Returns the address of the client used to reach the server with the specified index
+    pub fn address(&self, server: usize) -> Option<Ip> {
+        self.servers.get(server).map(|s| s.source)
+    }
+
+    /// Changes the address of the client used to reach the server with the specified index. If
+    /// it's the server in use, all the active mappings are requested again from the new address
+    pub fn set_address(&mut self, server: usize, addr: Ip, now: Instant) {
+        let old: IpAddr = match self.servers.get_mut(server) {
+            Some(server) => std::mem::replace(&mut server.source, addr).into(),
+            None => return,
+        };
+        let new: IpAddr = addr.into();
+        if old == new || server != self.server {
+            return;
+        }
+        for id in self.mappings.ids() {
+            if let State::Starting(_) | State::Running | State::Updating(..) =
+                self.mapping(id).state
+            {
+                let alert = Alert::ClientAddressChanged { old, new };
+                self.events.push_back(SessionEvent::Alert(id, alert));
+            }
+        }
+        // The mappings on the server still refer to the old address
+        self.server_lost_state(now);
+    }
+
+    /// Returns the next datagram that has to be sent, along with the index of the server it has
+    /// to be sent to
+    pub fn poll_transmit(&mut self) -> Option<(usize, Vec<u8>)> {
+        self.transmits.pop_front()
+    }
+
+    /// Returns the next notification for the application
+    pub fn poll_event(&mut self) -> Option<SessionEvent> {
+        self.events.pop_front()
+    }
+
+    /// Returns the instant at which `handle_timeout` has to be called next, if any timer is
+    /// active
+    pub fn poll_timeout(&self) -> Option<Instant> {
+        self.timers.next_deadline()
+    }
+
+    /// Returns the state of the mapping with the specified id
+    pub fn state(&self, id: MappingId) -> Option<State> {
+        self.mappings.get(id).map(|m| m.state)
+    }
+
+    /// Returns the version of the protocol used with the server, 0 means that NAT-PMP is used
+    pub fn version(&self) -> u8 {
+        self.servers[self.server].version
+    }
+
+    /// Returns the last epoch received from the server
+    pub fn epoch(&self) -> Option<u32> {
+        self.epoch.epoch()
+    }
+
+    /// Returns the number of mappings currently stored
+    pub fn len(&self) -> usize {
+        self.mappings.len()
+    }
+
+    /// Tells if there are no mappings stored
+    pub fn is_empty(&self) -> bool {
+        self.mappings.is_empty()
+    }
+
+    /// Returns the nonce of the mapping with the specified id
+    pub fn nonce(&self, id: MappingId) -> Option<[u8; 12]> {
+        self.mappings.get(id).map(|m| m.nonce())
+    }
+
+    /// Returns the mappings that are active on the server (or are being requested) as they have
+    /// to be saved to request them again after a restart, with their nonces (see the `store`
+    /// module)
+    pub fn stored(&self, now: Instant) -> Vec<StoredMapping<Ip>> {
+        let active = |m: &&MappingState| {
+            matches!(
+                m.state,
+                State::Starting(_) | State::Running | State::Updating(..)
+            )
+        };
+        self.mappings
+            .iter()
+            .map(|(_, m)| m)
+            .filter(active)
+            .filter_map(|m| {
+                let lifetime = Duration::from_secs(m.request.header.lifetime as u64);
+                Some(StoredMapping {
+                    mapping: Mapping::from_request(&m.request)?,
+                    kind: m.kind,
+                    assigned: m.assigned,
+                    remaining: (m.granted + lifetime).saturating_duration_since(now),
+                })
+            })
+            .collect()
+    }
+
+    /// Generates a nonce with the OS random number generator, making sure that it isn't used by
+    /// any other mapping
+    fn generate_nonce(&self) -> [u8; 12] {
+        let mut buf = [0; 12];
+        loop {
+            OsRng.fill_bytes(&mut buf);
+            if !self.nonces.contains_key(&buf) {
+                return buf;
+            }
+        }
+    }
+
+    /// Returns the data of the mapping, the id must be valid
+    fn mapping(&mut self, id: MappingId) -> &mut MappingState {
+        self.mappings.get_mut(id).expect("invalid mapping id")
+    }
+
+    /// Sets the state of the mapping and notifies the application of the change
+    fn set_state(&mut self, id: MappingId, state: State) {
+        self.mapping(id).state = state;
+        self.events.push_back(SessionEvent::StateChange(id, state));
+    }
+
+    /// Queues a datagram for the current server
+    fn send(&mut self, datagram: Vec<u8>) {
+        self.transmits.push_back((self.server, datagram));
+    }
+
+    /// Queues the request of the mapping for transmission
+    fn transmit(&mut self, id: MappingId) {
+        let Server { source, version } = self.servers[self.server];
+        if version != natpmp::VERSION {
+            let mapping = self.mapping(id);
+            mapping.set_version(version);
+            mapping.set_address(source.into());
+            let buffer = mapping.buffer();
+            return self.send(buffer);
+        }
+        // The mappings that can't be translated are never started (see `unsupported`)
+        if let Ok(request) = natpmp::MappingRequest::try_from(&self.mapping(id).request) {
+            self.send(request.bytes().to_vec());
+        }
+        // Ask for the external address along with the mappings, until it's known
+        if self.external.is_none() {
+            let request = natpmp::ExternalAddressRequest.bytes();
+            self.send(request.to_vec());
+        }
+    }
+
+    /// Counts a retransmission without response, if there have been too many and no server has
+    /// been selected yet the next one is tried
+    fn retransmission(&mut self) {
+        if self.selected || self.servers.len() < 2 {
+            return;
+        }
+        self.unanswered += 1;
+        if self.unanswered >= FAILOVER_COUNT {
+            self.server = (self.server + 1) % self.servers.len();
+            self.unanswered = 0;
+        }
+    }
+
+    /// A datagram came from the server with the specified index, returns `false` if it has to be
+    /// ignored as another server has already been selected
+    fn responded(&mut self, server: usize) -> bool {
+        if server >= self.servers.len() || self.selected && server != self.server {
+            return false;
+        }
+        // It might be a late response from a server that was given up on
+        self.server = server;
+        self.selected = true;
+        self.unanswered = 0;
+        true
+    }
+
+    /// When the server speaks NAT-PMP, returns the result code that tells why the mapping can't
+    /// be requested, if it can't
+    fn unsupported(&mut self, id: MappingId) -> Option<ResultCode> {
+        if self.version() != natpmp::VERSION {
+            return None;
+        }
+        natpmp::MappingRequest::try_from(&self.mapping(id).request).err()
+    }
+
+    /// Stores the new mapping, starts its retransmission timer and queues its request
+    fn insert(&mut self, request: RequestPacket, kind: RequestType, now: Instant) -> MappingId {
+        let mapping = MappingState::new(request, kind, now);
+        let nonce = mapping.nonce();
+        let id = self.mappings.insert(mapping);
+        self.nonces.entry(nonce).or_default().push(id);
+
+        if let Some(code) = self.unsupported(id) {
+            self.set_state(id, State::Error(code));
+            return id;
+        }
+        self.start(id, now);
+        id
+    }
+
+    /// Sends the request of the mapping for the first time (of this exchange with the server)
+    /// and starts its retransmission timer
+    fn start(&mut self, id: MappingId, now: Instant) {
+        let rt = self.policy.initial_rt(&mut self.rng);
+        let mapping = self.mapping(id);
+        mapping.rt = rt;
+        mapping.sent = now;
+        self.timers.schedule(Timer::Mapping(id), now + rt);
+        self.set_state(id, State::Starting(0));
+        self.transmit(id);
+    }
+
+    /// Requests any type of mapping, the returned value is the id of the mapping
+    pub fn request(&mut self, map: Mapping<Ip>, kind: RequestType, now: Instant) -> MappingId {
+        match map {
+            Mapping::Inbound(map) => self.request_inbound(map, kind, now),
+            Mapping::Outbound(map) => self.request_outbound(map, kind, now),
+        }
+    }
+
+    /// Requests an inbound mapping, the returned value is the id of the mapping
+    pub fn request_inbound(
+        &mut self,
+        map: InboundMap<Ip>,
+        kind: RequestType,
+        now: Instant,
+    ) -> MappingId {
+        // Count the number of options
+        let mut cap = map.filters.len();
+        if map.prefer_failure {
+            cap += 1
+        };
+        if map.third_party.is_some() {
+            cap += 1
+        };
+
+        // Insert all the options in one vector
+        let mut options = Vec::with_capacity(cap);
+        map.filters.into_iter().for_each(|f| {
+            options.push(PacketOption::filter(
+                // TODO: sposta questo all'interno
+                f.prefix + 128 - Ip::LENGTH,
+                f.remote_port,
+                f.remote_addr.into(),
+            ))
+        });
+        if map.prefer_failure {
+            options.push(PacketOption::prefer_failure())
+        };
+        if let Some(addr) = map.third_party {
+            options.push(PacketOption::third_party(addr.into()))
+        }
+
+        // Construct the request
+        let request = RequestPacket::map(
+            VERSION,
+            map.lifetime,
+            self.servers[self.server].source.into(),
+            map.nonce.unwrap_or_else(|| self.generate_nonce()),
+            map.protocol,
+            map.internal_port,
+            map.external_port.unwrap_or(0),
+            map.external_addr.unwrap_or(Ip::UNSPECIFIED).into(),
+            options,
+        )
+        .unwrap();
+
+        self.insert(request, kind, now)
+    }
+
+    /// Requests an outbound mapping, the returned value is the id of the mapping
+    pub fn request_outbound(
+        &mut self,
+        map: OutboundMap<Ip>,
+        kind: RequestType,
+        now: Instant,
+    ) -> MappingId {
+        // Construct a vector with all the options
+        let options = match map.third_party {
+            Some(addr) => vec![PacketOption::third_party(addr.into())],
+            None => Vec::new(),
+        };
+
+        // Construct the request
+        let request = RequestPacket::peer(
+            VERSION,
+            map.lifetime,
+            self.servers[self.server].source.into(),
+            map.nonce.unwrap_or_else(|| self.generate_nonce()),
+            map.protocol,
+            map.internal_port,
+            map.external_port.unwrap_or(0),
+            map.external_addr.unwrap_or(Ip::UNSPECIFIED).into(),
+            map.remote_port,
+            map.remote_addr.into(),
+            options,
+        )
+        .unwrap();
+
+        self.insert(request, kind, now)
+    }
+
+    /// Renews the mapping for the specified lifetime
+    pub fn renew(&mut self, id: MappingId, lifetime: u32, now: Instant) {
+        let mapping = match self.mappings.get_mut(id) {
+            Some(mapping) => mapping,
+            None => return,
+        };
+        // Update the lifetime
+        mapping.set_lifetime(lifetime);
+        if let Some(code) = self.unsupported(id) {
+            self.timers.cancel(Timer::Mapping(id));
+            return self.set_state(id, State::Error(code));
+        }
+        self.start(id, now);
+    }
+
+    /// Revokes the mapping, which is `Deleting` until the server confirms the deletion
+    /// (`Revoked`) or refuses it (`Error`)
+    pub fn revoke(&mut self, id: MappingId, now: Instant) {
+        match self.state(id) {
+            Some(State::Starting(_)) | Some(State::Running) | Some(State::Updating(..)) => {
+                self.delete(id, State::Deleting(0), now)
+            }
+            // There is nothing on the server, or it's already being deleted
+            _ => (),
+        }
+    }
+
+    /// Removes the mapping, as its handle has been dropped
+    pub fn remove(&mut self, id: MappingId, now: Instant) {
+        match self.state(id) {
+            Some(State::Starting(_)) | Some(State::Running) | Some(State::Updating(..)) => {
+                self.delete(id, State::Dropped(0), now)
+            }
+            // The deletion goes on, but the mapping is forgotten once it ends
+            Some(State::Deleting(n)) => self.set_state(id, State::Dropped(n)),
+            Some(State::Dropped(_)) | None => (),
+            Some(_) => self.forget(id),
+        }
+    }
+
+    /// Sends the request to delete the mapping (with a lifetime of 0) and starts its
+    /// retransmission timer. The mapping is kept until the server responds, so that the
+    /// response isn't mistaken for an unmatched one
+    fn delete(&mut self, id: MappingId, state: State, now: Instant) {
+        let rt = self.policy.initial_rt(&mut self.rng);
+        let mapping = self.mapping(id);
+        let lifetime = Duration::from_secs(mapping.request.header.lifetime as u64);
+        // The last request sent might have been received just now
+        mapping.expires = match mapping.state {
+            State::Running => mapping.granted + lifetime,
+            _ => now + lifetime,
+        };
+        mapping.set_lifetime(0);
+        mapping.rt = rt;
+        mapping.sent = now;
+        let deadline = (now + rt).min(mapping.expires);
+        self.timers.schedule(Timer::Mapping(id), deadline);
+        self.set_state(id, state);
+        self.transmit(id);
+    }
+
+    /// Removes all the mappings that might exist on the server (like `remove`), usually before
+    /// the client is stopped. The deletions confirmed by the server are notified with
+    /// `SessionEvent::Deleted`, the returned value contains the ids of the mappings deleted
+    /// along with how they were requested
+    pub fn remove_all(&mut self, now: Instant) -> Vec<(MappingId, Mapping<Ip>)> {
+        let mut removed = Vec::new();
+        for id in self.mappings.ids() {
+            let mapping = self.mapping(id);
+            if let State::Error(_) | State::Expired | State::Revoked = mapping.state {
+                continue;
+            }
+            if let Some(map) = Mapping::from_request(&mapping.request) {
+                removed.push((id, map));
+            }
+            self.remove(id, now);
+        }
+        removed
+    }
+
+    /// Forgets a dropped mapping, as nothing refers to it anymore
+    fn forget(&mut self, id: MappingId) {
+        self.timers.cancel(Timer::Mapping(id));
+        if let Some(mapping) = self.mappings.remove(id) {
+            let nonce = mapping.nonce();
+            if let Some(ids) = self.nonces.get_mut(&nonce) {
+                ids.retain(|&other| other != id);
+                if ids.is_empty() {
+                    self.nonces.remove(&nonce);
+                }
+            }
+        }
+    }
+
+    /// Sends an announce request to the server, its outcome is notified with
+    /// `SessionEvent::Announced`. If a request is already waiting for a response nothing is sent,
+    /// and the same response will be notified.
+    pub fn announce(&mut self, now: Instant) {
+        if self.probe.is_some() {
+            return;
+        }
+        let rt = self.policy.initial_rt(&mut self.rng);
+        self.probe = Some(Probe {
+            sent: now,
+            rt,
+            count: 1,
+        });
+        self.transmit_announce();
+        self.timers.schedule(Timer::Announce, now + rt);
+    }
+
+    /// Enables (or disables, with `None`) the health-check: while there are no active mappings
+    /// an announce request is sent every `interval`, so that a reboot of the server is detected
+    /// through its epoch even when there aren't any responses to mappings
+    pub fn set_health_check(&mut self, interval: Option<Duration>, now: Instant) {
+        self.health_check = interval;
+        match interval {
+            Some(interval) => self.timers.schedule(Timer::HealthCheck, now + interval),
+            None => self.timers.cancel(Timer::HealthCheck),
+        }
+    }
+
+    /// Queues the announce request for transmission, with NAT-PMP the external address is asked
+    /// instead
+    fn transmit_announce(&mut self) {
+        let request = match self.version() {
+            natpmp::VERSION => natpmp::ExternalAddressRequest.bytes().to_vec(),
+            version => {
+                RequestPacket::announce(version, self.servers[self.server].source.into()).bytes()
+            }
+        };
+        self.send(request);
+    }
+
+    /// Completes the pending announce request with the response received
+    fn answer_probe(&mut self, result: ResultCode, epoch: u32, now: Instant) {
+        if let Some(probe) = self.probe.take() {
+            self.timers.cancel(Timer::Announce);
+            let status = ServerStatus {
+                version: self.version(),
+                epoch,
+                rtt: now.saturating_duration_since(probe.sent),
+                result,
+            };
+            self.events.push_back(SessionEvent::Announced(Some(status)));
+        }
+    }
+
+    /// Tells if any of the mappings is active on the server (or is being requested)
+    fn has_active_mappings(&self) -> bool {
+        self.mappings.iter().any(|(_, m)| {
+            matches!(
+                m.state,
+                State::Starting(_) | State::Running | State::Updating(..)
+            )
+        })
+    }
+
+    /// Processes the timers that are due at the specified instant
+    pub fn handle_timeout(&mut self, now: Instant) {
+        while let Some(timer) = self.timers.pop_expired(now) {
+            match timer {
+                Timer::Mapping(id) => self.timer_expired(id, now),
+                Timer::Announce => self.announce_expired(now),
+                Timer::HealthCheck => {
+                    // The responses to the mappings already carry the epoch
+                    if !self.has_active_mappings() {
+                        self.announce(now);
+                    }
+                    if let Some(interval) = self.health_check {
+                        self.timers.schedule(Timer::HealthCheck, now + interval);
+                    }
+                }
+                // The epoch in the response will tell if the server really lost its state
+                Timer::Verify => self.announce(now),
+                Timer::Resend => self.resend_next(now),
+            }
+        }
+    }
+
+    /// The announce request hasn't been answered in time, it's either resent or the server is
+    /// considered unreachable
+    fn announce_expired(&mut self, now: Instant) {
+        let probe = match &mut self.probe {
+            Some(probe) if probe.count < ANNOUNCE_MRC => probe,
+            _ => {
+                self.probe = None;
+                return self.events.push_back(SessionEvent::Announced(None));
+            }
+        };
+        let rt = self.policy.next_rt(&mut self.rng, probe.rt);
+        probe.rt = rt;
+        probe.sent = now;
+        probe.count += 1;
+        self.retransmission();
+        self.transmit_announce();
+        self.timers.schedule(Timer::Announce, now + rt);
+    }
+
+    /// A timer of the mapping has ended
+    fn timer_expired(&mut self, id: MappingId, now: Instant) {
+        match self.mapping(id).state {
+            // The mapping was in a starting state, this means that the packet was
+            // already been sent n times but the server, still, didn't respond, thus
+            // the client will try to send it again
+            State::Starting(n) => {
+                let retransmitted = self.retransmit(id, n, State::Starting(n + 1), None, now);
+                if !retransmitted {
+                    self.set_state(id, State::Expired);
+                }
+            }
+            // If it's running it means that the lifetime has ended
+            State::Running => match self.mapping(id).kind {
+                RequestType::Once | RequestType::Repeat(0) => self.set_state(id, State::Expired),
+                RequestType::Repeat(n) => {
+                    self.mapping(id).kind = RequestType::Repeat(n - 1);
+                    self.update_mapping(id, 0, now);
+                }
+                RequestType::KeepAlive => self.update_mapping(id, 0, now),
+            },
+            State::Updating(n, _) => self.update_mapping(id, n + 1, now),
+            // The server didn't confirm the deletion yet, once the mapping expires on its own
+            // there is nothing left to delete
+            State::Deleting(n) => {
+                let expires = self.mapping(id).expires;
+                if !self.retransmit(id, n, State::Deleting(n + 1), Some(expires), now) {
+                    self.set_state(id, State::Expired);
+                }
+            }
+            State::Dropped(n) => {
+                let expires = self.mapping(id).expires;
+                if !self.retransmit(id, n, State::Dropped(n + 1), Some(expires), now) {
+                    self.forget(id);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Sends the request of the mapping again, for the (n + 1)-th time, moving it to the
+    /// specified state and restarting its timer. Returns `false`, without sending anything, if
+    /// it has to be given up on as the policy (or the specified deadline) says so
+    fn retransmit(
+        &mut self,
+        id: MappingId,
+        n: usize,
+        state: State,
+        deadline: Option<Instant>,
+        now: Instant,
+    ) -> bool {
+        let sent = self.mapping(id).sent;
+        let max_count = self.policy.max_count();
+        let limit = self
+            .policy
+            .max_duration()
+            .map(|mrd| sent + mrd)
+            .into_iter()
+            .chain(deadline)
+            .min();
+        // The request has already been sent n + 1 times, or for too long
+        if max_count.is_some_and(|mrc| n + 1 >= mrc) || limit.is_some_and(|limit| now >= limit) {
+            return false;
+        }
+        self.set_state(id, state);
+        // Resend the packet, maybe to the next server
+        self.retransmission();
+        self.transmit(id);
+        // Restart the timer, the last one ends when the limit is reached
+        let rt_prev = self.mapping(id).rt;
+        let rt = self.policy.next_rt(&mut self.rng, rt_prev);
+        self.mapping(id).rt = rt;
+        let deadline = match limit {
+            Some(limit) => (now + rt).min(limit),
+            None => now + rt,
+        };
+        self.timers.schedule(Timer::Mapping(id), deadline);
+        true
+    }
+
+    /// Sends the n-th renewal of a running mapping in order to keep it alive, and starts the
+    /// timer of the next one (or of the expiration, if there won't be any other)
+    fn update_mapping(&mut self, id: MappingId, times: usize, now: Instant) {
+        let mapping = self.mapping(id);
+        let lifetime = mapping.request.header.lifetime;
+        let granted = mapping.granted;
+        let expires = granted + Duration::from_secs(lifetime as u64);
+        if now >= expires {
+            return self.set_state(id, State::Expired);
+        }
+        // Resend the request
+        self.transmit(id);
+        self.set_state(id, State::Updating(times, lifetime));
+        let next = self.renewal(granted, lifetime, times + 1);
+        self.timers.schedule(Timer::Mapping(id), next);
+    }
+
+    /// Returns when the n-th renewal of a mapping that has been assigned the specified lifetime
+    /// at the specified instant has to be sent, if it doesn't have to be renewed anymore the
+    /// instant of its expiration is returned
+    fn renewal(&mut self, granted: Instant, lifetime: u32, times: usize) -> Instant {
+        let lifetime = Duration::from_secs(lifetime as u64);
+        match self.policy.renewal(&mut self.rng, lifetime, times) {
+            Some(delay) if delay < lifetime => granted + delay,
+            _ => granted + lifetime,
+        }
+    }
+
+    /// Processes a datagram received from the PCP server with the specified index
+    pub fn handle_datagram(
+        &mut self,
+        server: usize,
+        data: &[u8],
+        now: Instant,
+    ) -> Result<(), ParsingError> {
+        // NAT-PMP packets start with the version 0, and it's only used with IPv4
+        if data.first() == Some(&natpmp::VERSION) && Ip::LENGTH == 32 {
+            if !self.responded(server) {
+                return Ok(());
+            }
+            return self.handle_natpmp(data, now);
+        }
+        let header = ResponseHeaderSlice::try_from(data)?;
+        let (result, lifetime, epoch) = (header.result_code(), header.lifetime(), header.epoch());
+
+        // An unsolicited announce (possibly multicast) isn't trusted until it's verified, and
+        // it's only relevant if it comes from the server in use
+        if header.opcode() == OpCode::Announce && self.probe.is_none() {
+            if server != self.server {
+                return Ok(());
+            }
+            // Announce error responses shouldn't even be sent, if one arrives it gets ignored
+            if result == ResultCode::Success && !self.timers.is_active(Timer::Verify) {
+                let delay = self.rng.gen_range(0, ANNOUNCE_DELAY + 1);
+                self.timers
+                    .schedule(Timer::Verify, now + Duration::from_millis(delay));
+            }
+            return Ok(());
+        }
+        if !self.responded(server) {
+            return Ok(());
+        }
+        // When a response packet is received, always check if the epoch is valid, the answer to
+        // an announce request is reported anyway
+        let valid = self.validate_epoch(epoch, now);
+        if !valid && header.opcode() != OpCode::Announce {
+            return Ok(());
+        }
+        // The server doesn't speak this version of PCP, the response might lack the payload
+        if result == ResultCode::UnsuppVersion {
+            self.negotiate(header.version(), epoch, now);
+            return Ok(());
+        }
+        let response = match header.version() {
+            1 => ResponsePacket::parse_v1(data)?,
+            _ => ResponsePacketSlice::try_from(data)?.parse(),
+        };
+        match header.opcode() {
+            // The response to an announce request made by the client
+            OpCode::Announce => self.answer_probe(result, epoch, now),
+            OpCode::Map | OpCode::Peer => match self.find_mapping(&response) {
+                Some(id) => {
+                    // The response might be unsolicited, sent by the server because the
+                    // external address changed
+                    let (assigned, peer) = match response.payload {
+                        ResponsePayload::Map(p) => (
+                            Some(SocketAddr::new(p.external_address, p.external_port)),
+                            None,
+                        ),
+                        ResponsePayload::Peer(p) => (
+                            Some(SocketAddr::new(p.external_address, p.external_port)),
+                            Some(p),
+                        ),
+                        ResponsePayload::Announce => (None, None),
+                    };
+                    self.mapping_response(id, result, lifetime, assigned, peer, now)
+                }
+                None => self.events.push_back(SessionEvent::Unmatched(response)),
+            },
+        }
+        Ok(())
+    }
+
+    /// Finds the mapping the response refers to
+    fn find_mapping(&self, response: &ResponsePacket) -> Option<MappingId> {
+        let matches = |id: &MappingId| {
+            let request = &self.mappings.get(*id).expect("invalid mapping id").request;
+            correlates(request, response)
+        };
+        // The responses of the version 1 don't have a nonce
+        if response.header.version == 1 {
+            return self.mappings.ids().into_iter().find(matches);
+        }
+        let nonce = match &response.payload {
+            ResponsePayload::Map(p) => p.nonce,
+            ResponsePayload::Peer(p) => p.nonce,
+            ResponsePayload::Announce => return None,
+        };
+        // Find the request with the same nonce that matches the response
+        self.nonces.get(&nonce)?.iter().copied().find(matches)
+    }
+
+    /// The server doesn't support the version of the requests, it suggested (in the response)
+    /// the highest version it supports: if it's one of the versions of PCP supported the requests
+    /// are sent again with it, otherwise there is nothing left to try (a server that only speaks
+    /// NAT-PMP answers with a NAT-PMP packet instead)
+    fn negotiate(&mut self, server: u8, epoch: u32, now: Instant) {
+        match server {
+            // Late response to a request sent before the version was changed
+            v if v == self.version() => return,
+            v if (1..=VERSION).contains(&v) => {
+                self.servers[self.server].version = v;
+                self.server_lost_state(now);
+            }
+            // A later version of PCP, or none at all
+            _ => return self.unsupported_version(epoch, now),
+        }
+        // Ask again in the version now used
+        if self.probe.is_some() {
+            self.transmit_announce();
+        }
+    }
+
+    /// The server doesn't speak any of the versions of PCP supported: the active mappings end
+    /// with an error, as well as the announce request
+    fn unsupported_version(&mut self, epoch: u32, now: Instant) {
+        for id in self.mappings.ids() {
+            if let State::Starting(_) | State::Running | State::Updating(..) =
+                self.mapping(id).state
+            {
+                self.timers.cancel(Timer::Mapping(id));
+                self.set_state(id, State::Error(ResultCode::UnsuppVersion));
+            }
+        }
+        self.answer_probe(ResultCode::UnsuppVersion, epoch, now);
+    }
+
+    /// Processes a NAT-PMP datagram received from the gateway
+    fn handle_natpmp(&mut self, data: &[u8], now: Instant) -> Result<(), ParsingError> {
+        let packet = natpmp::ResponseSlice::try_from(data)?;
+        let result = packet.result_code();
+
+        if !self.validate_epoch(packet.epoch(), now) {
+            return Ok(());
+        }
+        // The gateway doesn't speak PCP, it either responded to a request or announced its
+        // external address
+        if self.version() != natpmp::VERSION {
+            self.fall_back(now);
+        }
+        // The gateway answered the announce request (sent as an external address request, or
+        // rejected because it was in PCP)
+        if packet.opcode() == natpmp::OpCode::ExternalAddress
+            || result == natpmp::ResultCode::UnsuppVersion
+        {
+            self.answer_probe(result.into(), packet.epoch(), now);
+        }
+        // Response to a request of another version, or an error without payload
+        if result == natpmp::ResultCode::UnsuppVersion {
+            return Ok(());
+        }
+        match packet.payload() {
+            natpmp::ResponsePayload::ExternalAddress(addr) => {
+                let addr = IpAddr::V4(addr);
+                if result != natpmp::ResultCode::Success || self.external == Some(addr) {
+                    return Ok(());
+                }
+                self.external = Some(addr);
+                // Tell the running mappings their (new) external address
+                for id in self.mappings.ids() {
+                    let mapping = self.mapping(id);
+                    if let (State::Running, RequestPayload::Map(p)) =
+                        (mapping.state, &mapping.request.payload)
+                    {
+                        let external = SocketAddr::new(addr, p.external_port);
+                        let lifetime = mapping.request.header.lifetime;
+                        self.assign(id, external, lifetime, None);
+                    }
+                }
+            }
+            natpmp::ResponsePayload::Mapping(res) => {
+                let protocol = match packet.opcode() {
+                    natpmp::OpCode::MapUdp => ProtocolNumber::Udp,
+                    _ => ProtocolNumber::Tcp,
+                };
+                // There is no nonce, so the mapping is found by its protocol and internal port
+                let id = self.mappings.iter().find_map(|(id, m)| {
+                    let active = !matches!(m.state, State::Error(_) | State::Expired);
+                    match &m.request.payload {
+                        RequestPayload::Map(req)
+                            if active
+                                && req.protocol == protocol
+                                && req.internal_port == res.internal_port =>
+                        {
+                            Some(id)
+                        }
+                        _ => None,
+                    }
+                });
+                if let Some(id) = id {
+                    if result == natpmp::ResultCode::Success {
+                        // Renewals will suggest the port that was assigned
+                        self.mapping(id).set_external_port(res.external_port);
+                    }
+                    let assigned = self
+                        .external
+                        .map(|addr| SocketAddr::new(addr, res.external_port));
+                    self.mapping_response(id, result.into(), res.lifetime, assigned, None, now);
+                }
+            }
+            natpmp::ResponsePayload::Empty => (),
+        }
+        Ok(())
+    }
+
+    /// The server only speaks NAT-PMP, so all the active mappings are requested again with it
+    fn fall_back(&mut self, now: Instant) {
+        self.servers[self.server].version = natpmp::VERSION;
+        self.external = None;
+        self.server_lost_state(now);
+    }
+
+    /// Updates the mapping after a response from the server, `peer` is the payload of the
+    /// response to a PEER request
+    fn mapping_response(
+        &mut self,
+        id: MappingId,
+        result: ResultCode,
+        lifetime: u32,
+        assigned: Option<SocketAddr>,
+        peer: Option<PeerResponsePayload>,
+        now: Instant,
+    ) {
+        match self.mapping(id).state {
+            State::Deleting(_) | State::Dropped(_) => {
+                return self.deletion_response(id, result, lifetime)
+            }
+            // A late response to a request sent before the deletion
+            State::Revoked => return,
+            _ => (),
+        }
+        self.timers.cancel(Timer::Mapping(id));
+        match result {
+            ResultCode::Success => {
+                // It's not granted that the requested lifetime matches the assigned one
+                self.mapping(id).set_lifetime(lifetime);
+                // After a success response the mapping is running
+                self.set_state(id, State::Running);
+
+                if let Some(external) = assigned {
+                    self.assign(id, external, lifetime, peer);
+                }
+
+                // From now on the lifetime is counted from this response
+                self.mapping(id).granted = now;
+                let deadline = match self.mapping(id).kind {
+                    RequestType::Once | RequestType::Repeat(0) => {
+                        now + Duration::from_secs(lifetime as u64)
+                    }
+                    RequestType::KeepAlive | RequestType::Repeat(_) => {
+                        self.renewal(now, lifetime, 0)
+                    }
+                };
+                // Set the timer for when it has to be renewed or it expires
+                self.timers.schedule(Timer::Mapping(id), deadline);
+            }
+            // On an error response, se the state of the mapping
+            error => self.set_state(id, State::Error(error)),
+        }
+    }
+
+    /// Updates the mapping being deleted after a response from the server: a success with a
+    /// lifetime of 0 confirms the deletion, an error means that the server refused it
+    fn deletion_response(&mut self, id: MappingId, result: ResultCode, lifetime: u32) {
+        // A late response to a request sent before the deletion
+        if result == ResultCode::Success && lifetime != 0 {
+            return;
+        }
+        let dropped = matches!(self.mapping(id).state, State::Dropped(_));
+        match result {
+            ResultCode::Success if dropped => {
+                self.events.push_back(SessionEvent::Deleted(id));
+                self.forget(id);
+            }
+            _ if dropped => self.forget(id),
+            ResultCode::Success => {
+                self.timers.cancel(Timer::Mapping(id));
+                self.set_state(id, State::Revoked);
+            }
+            error => {
+                self.timers.cancel(Timer::Mapping(id));
+                self.set_state(id, State::Error(error));
+            }
+        }
+    }
+
+    /// Stores the external address assigned to the mapping and notifies the application, if
+    /// it's different from the one previously assigned the change is notified too. The whole
+    /// response to a PEER request is notified along with it
+    fn assign(
+        &mut self,
+        id: MappingId,
+        external: SocketAddr,
+        lifetime: u32,
+        peer: Option<PeerResponsePayload>,
+    ) {
+        let old = self.mapping(id).assigned.replace(external);
+        if let Some(old) = old.filter(|&old| old != external) {
+            let alert = Alert::ExternalAddressChanged { old, new: external };
+            self.events.push_back(SessionEvent::Alert(id, alert));
+        }
+        let alert = Alert::Assigned(external.ip(), external.port(), lifetime);
+        self.events.push_back(SessionEvent::Alert(id, alert));
+        if let Some(payload) = peer {
+            let alert = Alert::PeerAssigned(PeerAssignment { lifetime, payload });
+            self.events.push_back(SessionEvent::Alert(id, alert));
+        }
+    }
+
+    /// Validates the epoch according to the previous one and time elapsed since then (see the
+    /// `epoch` module), the server is reset if it's invalid
+    fn validate_epoch(&mut self, epoch: u32, now: Instant) -> bool {
+        let valid = self.epoch.update(epoch, now);
+        if !valid {
+            self.server_reset(now);
+        }
+        valid
+    }
+
+    /// When the epoch is invalid (which is also how an unsolicited announce is verified) it means
+    /// that the server has been restarted, and it might have been upgraded too: the highest
+    /// version of PCP is tried again while resending the mappings
+    fn server_reset(&mut self, now: Instant) {
+        self.servers[self.server].version = VERSION;
+        self.external = None;
+        self.events.push_back(SessionEvent::ServerReset);
+        self.server_lost_state(now);
+    }
+
+    /// The server lost it's internal state (or it's using a different version), so all of the
+    /// mappings will have to be resent
+    fn server_lost_state(&mut self, now: Instant) {
+        // Reset the state of each active mapping and queue it, the requests are paced so that
+        // the server isn't flooded
+        self.resend.clear();
+        for id in self.mappings.ids() {
+            match self.mapping(id).state {
+                State::Starting(_) | State::Running | State::Updating(..) => (),
+                _ => continue,
+            }
+            self.timers.cancel(Timer::Mapping(id));
+            if let Some(code) = self.unsupported(id) {
+                self.set_state(id, State::Error(code));
+                continue;
+            }
+            self.set_state(id, State::Starting(0));
+            self.resend.push_back(id);
+        }
+        self.resend_next(now);
+    }
+
+    /// Sends the request of the next mapping waiting to be sent again and starts its timer
+    fn resend_next(&mut self, now: Instant) {
+        while let Some(id) = self.resend.pop_front() {
+            // The mapping might have been removed or requested again in the meantime
+            let waiting = matches!(self.state(id), Some(State::Starting(0)))
+                && !self.timers.is_active(Timer::Mapping(id));
+            if !waiting {
+                continue;
+            }
+            self.start(id, now);
+            break;
+        }
+        if self.resend.is_empty() {
+            self.timers.cancel(Timer::Resend);
+        } else {
+            self.timers.schedule(Timer::Resend, now + RESEND_PACE);
+        }
+    }
+}
+
+/// Tells if the response refers to the request, following the rules of the RFC (sections 11.3
+/// and 12.3): the nonce, the protocol and the internal port must be the same, a PEER response
+/// must also have the same remote address and port, and the FILTER and THIRD_PARTY options of
+/// the two packets must be the same. The responses of the version 1 have no nonce.
+///
+/// An error response may lack some of the options, as the server may have stopped processing
+/// them, but it can't contain options that weren't in the request.
+fn correlates(request: &RequestPacket, response: &ResponsePacket) -> bool {
+    let nonce = |req: &[u8; 12], res: &[u8; 12]| response.header.version == 1 || req == res;
+    let payload = match (&request.payload, &response.payload) {
+        (RequestPayload::Map(req), ResponsePayload::Map(res)) => {
+            nonce(&req.nonce, &res.nonce)
+                && req.protocol == res.protocol
+                && req.internal_port == res.internal_port
+        }
+        (RequestPayload::Peer(req), ResponsePayload::Peer(res)) => {
+            nonce(&req.nonce, &res.nonce)
+                && req.protocol == res.protocol
+                && req.internal_port == res.internal_port
+                && req.remote_port == res.remote_port
+                && req.remote_address == res.remote_address
+        }
+        _ => false,
+    };
+    // Only these options describe the mapping, PREFER_FAILURE doesn't have to be echoed and
+    // the unknown ones are skipped
+    let relevant = |o: &&PacketOption| match o.header.code {
+        OptionCode::Filter | OptionCode::ThirdParty => true,
+        OptionCode::PreferFailure | OptionCode::Unknown(_) => false,
+    };
+    let requested: Vec<_> = request.options.iter().filter(relevant).collect();
+    let responded: Vec<_> = response.options.iter().filter(relevant).collect();
+
+    payload
+        && responded.iter().all(|o| requested.contains(o))
+        && (response.header.result != ResultCode::Success
+            || requested.iter().all(|o| responded.contains(o)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    /// Address of the client
+    const CLIENT: Ipv4Addr = Ipv4Addr::new(192, 168, 1, 10);
+    /// External address assigned by the server
+    const EXTERNAL: Ipv4Addr = Ipv4Addr::new(203, 0, 113, 5);
+    /// Epoch of the server when the tests start
+    const EPOCH: u32 = 1000;
+
+    /// Takes the next datagram to send, which must be for the first server
+    fn transmit(session: &mut PcpSession<Ipv4Addr>) -> Vec<u8> {
+        let (server, datagram) = session.poll_transmit().expect("no datagram to send");
+        assert_eq!(server, 0);
+        datagram
+    }
+
+    /// Takes all the events produced by the session
+    fn events(session: &mut PcpSession<Ipv4Addr>) -> Vec<SessionEvent> {
+        std::iter::from_fn(|| session.poll_event()).collect()
+    }
+
+    /// Builds the response to a PCP request, a MAP or PEER one is assigned the external port
+    fn reply(request: &[u8], result: ResultCode, epoch: u32, port: u16) -> Vec<u8> {
+        let mut response = request.to_vec();
+        response[1] |= 0x80;
+        response[2] = 0;
+        response[3] = result as u8;
+        response[8..12].copy_from_slice(&epoch.to_be_bytes());
+        response[12..24].iter_mut().for_each(|b| *b = 0);
+        if response.len() >= 60 {
+            response[42..44].copy_from_slice(&port.to_be_bytes());
+            response[44..60].copy_from_slice(&EXTERNAL.to_ipv6_mapped().octets());
+        }
+        response
+    }
+
+    /// Builds the `UnsuppVersion` response (without payload) of a server that speaks the
+    /// specified version
+    fn unsupported(request: &[u8], version: u8) -> Vec<u8> {
+        let mut response = reply(request, ResultCode::UnsuppVersion, EPOCH, 0);
+        response[0] = version;
+        response.truncate(24);
+        response
+    }
+
+    /// Epoch of the server at the specified instant
+    fn epoch(start: Instant, now: Instant) -> u32 {
+        EPOCH + (now - start).as_secs() as u32
+    }
+
+    /// Requests a TCP mapping of the port, which the server assigns (with the port 1000 higher)
+    /// when the session starts
+    fn running(session: &mut PcpSession<Ipv4Addr>, port: u16, now: Instant) -> MappingId {
+        let map = InboundMap::new(port, 120).protocol(ProtocolNumber::Tcp);
+        let id = session.request_inbound(map, RequestType::Once, now);
+        let request = transmit(session);
+        let response = reply(&request, ResultCode::Success, EPOCH, port + 1000);
+        session.handle_datagram(0, &response, now).unwrap();
+        assert_eq!(session.state(id), Some(State::Running));
+        events(session);
+        id
+    }
+
+    /// Builds an announce sent by the server on its own
+    fn announce(epoch: u32) -> Vec<u8> {
+        let request = RequestPacket::announce(VERSION, Ipv4Addr::UNSPECIFIED.into()).bytes();
+        reply(&request, ResultCode::Success, epoch, 0)
+    }
+
+    #[test]
+    fn request_success_renewal() {
+        let mut session = PcpSession::new(CLIENT);
+        let start = Instant::now();
+        let map = InboundMap::new(6000, 120).protocol(ProtocolNumber::Tcp);
+        let id = session.request_inbound(map, RequestType::KeepAlive, start);
+        let request = transmit(&mut session);
+        assert!(session.poll_transmit().is_none());
+        assert_eq!(session.state(id), Some(State::Starting(0)));
+
+        let response = reply(&request, ResultCode::Success, EPOCH, 7000);
+        session.handle_datagram(0, &response, start).unwrap();
+        assert_eq!(session.state(id), Some(State::Running));
+        assert!(events(&mut session).iter().any(|event| matches!(
+            event,
+            SessionEvent::Alert(i, Alert::Assigned(addr, 7000, 120))
+                if *i == id && *addr == IpAddr::from(EXTERNAL)
+        )));
+
+        // The first renewal is sent between 1/2 and 5/8 of the lifetime
+        let renewal = session.poll_timeout().unwrap();
+        assert!(renewal >= start + Duration::from_secs(60));
+        assert!(renewal <= start + Duration::from_secs(75));
+        session.handle_timeout(renewal);
+        let request = transmit(&mut session);
+        assert_eq!(request[24..36], response[24..36], "same nonce");
+        assert_eq!(session.state(id), Some(State::Updating(0, 120)));
+
+        let epoch = epoch(start, renewal);
+        let response = reply(&request, ResultCode::Success, epoch, 7000);
+        session.handle_datagram(0, &response, renewal).unwrap();
+        assert_eq!(session.state(id), Some(State::Running));
+        assert!(session.poll_timeout().unwrap() >= renewal + Duration::from_secs(60));
+    }
+
+    #[test]
+    fn retransmitted_until_answered() {
+        let mut session = PcpSession::new(CLIENT);
+        let start = Instant::now();
+        let map = InboundMap::new(6000, 120).protocol(ProtocolNumber::Udp);
+        let id = session.request_inbound(map, RequestType::Once, start);
+        let request = transmit(&mut session);
+
+        // The request is resent after about 3 seconds, then after about 6 more
+        let first = session.poll_timeout().unwrap();
+        assert!(first >= start + Duration::from_millis(2700));
+        assert!(first <= start + Duration::from_millis(3300));
+        session.handle_timeout(first);
+        assert_eq!(transmit(&mut session), request);
+        assert_eq!(session.state(id), Some(State::Starting(1)));
+        let second = session.poll_timeout().unwrap();
+        assert!(second - first >= (first - start).mul_f32(2.0 * 0.9));
+        assert!(second - first <= (first - start).mul_f32(2.0 * 1.1));
+
+        let response = reply(&request, ResultCode::Success, EPOCH, 7000);
+        session.handle_datagram(0, &response, first).unwrap();
+        assert_eq!(session.state(id), Some(State::Running));
+        // Only the lifetime is left to wait
+        assert_eq!(
+            session.poll_timeout(),
+            Some(first + Duration::from_secs(120))
+        );
+    }
+
+    #[test]
+    fn error_response() {
+        let mut session = PcpSession::new(CLIENT);
+        let start = Instant::now();
+        let map = InboundMap::new(6000, 120).protocol(ProtocolNumber::Tcp);
+        let id = session.request_inbound(map, RequestType::Once, start);
+        let request = transmit(&mut session);
+
+        let response = reply(&request, ResultCode::NotAuthorized, EPOCH, 0);
+        session.handle_datagram(0, &response, start).unwrap();
+        assert_eq!(
+            session.state(id),
+            Some(State::Error(ResultCode::NotAuthorized))
+        );
+        assert_eq!(session.poll_timeout(), None);
+        assert!(session.poll_transmit().is_none());
+    }
+
+    #[test]
+    fn response_correlated_by_nonce() {
+        let mut session = PcpSession::new(CLIENT);
+        let now = Instant::now();
+        let map = InboundMap::new(6000, 120).protocol(ProtocolNumber::Tcp);
+        let id = session.request_inbound(map, RequestType::Once, now);
+        let request = transmit(&mut session);
+        events(&mut session);
+
+        // Another nonce
+        let mut other = request.clone();
+        other[24] ^= 0xff;
+        let response = reply(&other, ResultCode::Success, EPOCH, 7000);
+        session.handle_datagram(0, &response, now).unwrap();
+        // The same nonce, but another internal port
+        let mut other = request.clone();
+        other[40..42].copy_from_slice(&6001u16.to_be_bytes());
+        let response = reply(&other, ResultCode::Success, EPOCH, 7000);
+        session.handle_datagram(0, &response, now).unwrap();
+
+        let events = events(&mut session);
+        assert_eq!(events.len(), 2);
+        assert!(events
+            .iter()
+            .all(|event| matches!(event, SessionEvent::Unmatched(_))));
+        assert_eq!(session.state(id), Some(State::Starting(0)));
+
+        let response = reply(&request, ResultCode::Success, EPOCH, 7000);
+        session.handle_datagram(0, &response, now).unwrap();
+        assert_eq!(session.state(id), Some(State::Running));
+    }
+
+    #[test]
+    fn lower_version() {
+        let mut session = PcpSession::new(CLIENT);
+        let now = Instant::now();
+        let map = InboundMap::new(6000, 120).protocol(ProtocolNumber::Tcp);
+        let id = session.request_inbound(map, RequestType::Once, now);
+        let request = transmit(&mut session);
+
+        // The server only speaks the version 1
+        let response = unsupported(&request, 1);
+        session.handle_datagram(0, &response, now).unwrap();
+        assert_eq!(session.version(), 1);
+        let request = transmit(&mut session);
+        assert_eq!(request[0], 1);
+
+        let response = reply(&request, ResultCode::Success, EPOCH, 0);
+        session.handle_datagram(0, &response, now).unwrap();
+        assert_eq!(session.state(id), Some(State::Running));
+    }
+
+    #[test]
+    fn higher_version() {
+        let mut session = PcpSession::new(CLIENT);
+        let now = Instant::now();
+        let map = InboundMap::new(6000, 120).protocol(ProtocolNumber::Tcp);
+        let id = session.request_inbound(map, RequestType::Once, now);
+        let request = transmit(&mut session);
+
+        // The server speaks PCP, but not this version nor NAT-PMP
+        session
+            .handle_datagram(0, &unsupported(&request, 3), now)
+            .unwrap();
+        assert_eq!(session.version(), VERSION);
+        assert_eq!(
+            session.state(id),
+            Some(State::Error(ResultCode::UnsuppVersion))
+        );
+        assert!(session.poll_transmit().is_none());
+        assert_eq!(session.poll_timeout(), None);
+    }
+
+    #[test]
+    fn version_of_each_server() {
+        let mut session = PcpSession::new(CLIENT);
+        session.add_server(CLIENT);
+        let mut now = Instant::now();
+        let map = InboundMap::new(6000, 120).protocol(ProtocolNumber::Tcp);
+        session.request_inbound(map, RequestType::Once, now);
+        transmit(&mut session);
+
+        // The first server doesn't answer, the second one only speaks the version 1
+        while session.server() == 0 {
+            now = session.poll_timeout().unwrap();
+            session.handle_timeout(now);
+        }
+        // The retransmissions to the first server are still queued
+        let transmits = std::iter::from_fn(|| session.poll_transmit());
+        let (server, request) = transmits.last().unwrap();
+        assert_eq!(server, 1);
+        session
+            .handle_datagram(1, &unsupported(&request, 1), now)
+            .unwrap();
+        assert_eq!(session.version(), 1);
+        assert_eq!(session.servers[0].version, VERSION);
+        assert_eq!(session.servers[1].version, 1);
+        let (server, request) = session.poll_transmit().unwrap();
+        assert_eq!((server, request[0]), (1, 1));
+    }
+
+    #[test]
+    fn version_after_reset() {
+        let mut session = PcpSession::new(CLIENT);
+        let start = Instant::now();
+        let map = InboundMap::new(6000, 120).protocol(ProtocolNumber::Tcp);
+        let id = session.request_inbound(map, RequestType::Once, start);
+        let request = transmit(&mut session);
+        session
+            .handle_datagram(0, &unsupported(&request, 1), start)
+            .unwrap();
+        let request = transmit(&mut session);
+        let response = reply(&request, ResultCode::Success, EPOCH, 0);
+        session.handle_datagram(0, &response, start).unwrap();
+        assert_eq!(session.state(id), Some(State::Running));
+
+        // The server restarted, it might have been upgraded
+        let now = start + Duration::from_secs(10);
+        session.announce(now);
+        let request = transmit(&mut session);
+        assert_eq!(request[0], 1);
+        let response = reply(&request, ResultCode::Success, 5, 0);
+        session.handle_datagram(0, &response, now).unwrap();
+        assert_eq!(session.version(), VERSION);
+        assert_eq!(transmit(&mut session)[0], VERSION);
+    }
+
+    #[test]
+    fn natpmp_fallback() {
+        let mut session = PcpSession::new(CLIENT);
+        let now = Instant::now();
+        let map = InboundMap::new(6000, 120).protocol(ProtocolNumber::Tcp);
+        let id = session.request_inbound(map, RequestType::Once, now);
+        transmit(&mut session);
+
+        // The gateway answers in NAT-PMP that it doesn't speak PCP
+        let mut unsupported = vec![0, 128 + 1, 0, 1];
+        unsupported.extend_from_slice(&EPOCH.to_be_bytes());
+        session.handle_datagram(0, &unsupported, now).unwrap();
+        assert_eq!(session.version(), natpmp::VERSION);
+
+        // The mapping is requested again, along with the external address
+        let request = transmit(&mut session);
+        assert_eq!(request[..2], [0, 2]);
+        assert_eq!(request[4..6], 6000u16.to_be_bytes());
+        assert_eq!(transmit(&mut session), [0, 0]);
+        events(&mut session);
+
+        let mut response = vec![0, 128 + 2, 0, 0];
+        response.extend_from_slice(&EPOCH.to_be_bytes());
+        response.extend_from_slice(&6000u16.to_be_bytes());
+        response.extend_from_slice(&7000u16.to_be_bytes());
+        response.extend_from_slice(&120u32.to_be_bytes());
+        session.handle_datagram(0, &response, now).unwrap();
+        assert_eq!(session.state(id), Some(State::Running));
+        // The external address isn't known yet
+        assert!(!events(&mut session)
+            .iter()
+            .any(|event| matches!(event, SessionEvent::Alert(_, Alert::Assigned(..)))));
+
+        let mut response = vec![0, 128, 0, 0];
+        response.extend_from_slice(&EPOCH.to_be_bytes());
+        response.extend_from_slice(&EXTERNAL.octets());
+        session.handle_datagram(0, &response, now).unwrap();
+        assert!(events(&mut session).iter().any(|event| matches!(
+            event,
+            SessionEvent::Alert(i, Alert::Assigned(addr, 7000, 120))
+                if *i == id && *addr == IpAddr::from(EXTERNAL)
+        )));
+    }
+
+    #[test]
+    fn deletion_confirmed() {
+        let mut session = PcpSession::new(CLIENT);
+        let now = Instant::now();
+        let id = running(&mut session, 6000, now);
+
+        let later = now + Duration::from_secs(1);
+        session.revoke(id, later);
+        assert_eq!(session.state(id), Some(State::Deleting(0)));
+        let request = transmit(&mut session);
+        assert_eq!(request[4..8], [0; 4], "lifetime of 0");
+
+        let response = reply(&request, ResultCode::Success, epoch(now, later), 7000);
+        session.handle_datagram(0, &response, later).unwrap();
+        assert_eq!(session.state(id), Some(State::Revoked));
+        assert_eq!(session.poll_timeout(), None);
+    }
+
+    #[test]
+    fn unsolicited_announce_verified() {
+        let mut session = PcpSession::new(CLIENT);
+        let start = Instant::now();
+        let id = running(&mut session, 6000, start);
+
+        // The announce isn't trusted until it's verified, within 5 seconds
+        let now = start + Duration::from_secs(10);
+        session.handle_datagram(0, &announce(1), now).unwrap();
+        assert!(session.poll_transmit().is_none());
+        assert!(session.poll_event().is_none());
+        assert_eq!(session.state(id), Some(State::Running));
+        let verify = session.poll_timeout().unwrap();
+        assert!(verify >= now && verify <= now + Duration::from_millis(ANNOUNCE_DELAY));
+        // Another one doesn't delay the verification
+        session.handle_datagram(0, &announce(1), verify).unwrap();
+        assert_eq!(session.poll_timeout(), Some(verify));
+
+        session.handle_timeout(verify);
+        let request = transmit(&mut session);
+        assert_eq!(request.len(), 24);
+        assert_eq!(request[1], OpCode::Announce as u8);
+
+        // The epoch is the expected one, the server didn't lose the mappings
+        let epoch = epoch(start, verify);
+        session
+            .handle_datagram(0, &reply(&request, ResultCode::Success, epoch, 0), verify)
+            .unwrap();
+        let events = events(&mut session);
+        assert!(matches!(events[..], [SessionEvent::Announced(Some(_))]));
+        assert!(session.poll_transmit().is_none());
+        assert_eq!(session.state(id), Some(State::Running));
+    }
+
+    #[test]
+    fn unsolicited_announce_after_reset() {
+        let mut session = PcpSession::new(CLIENT);
+        let start = Instant::now();
+        let ids: Vec<_> = (6000..6003)
+            .map(|port| running(&mut session, port, start))
+            .collect();
+
+        let now = start + Duration::from_secs(10);
+        session.handle_datagram(0, &announce(1), now).unwrap();
+        let verify = session.poll_timeout().unwrap();
+        session.handle_timeout(verify);
+        let request = transmit(&mut session);
+
+        // The server restarted
+        session
+            .handle_datagram(0, &reply(&request, ResultCode::Success, 5, 0), verify)
+            .unwrap();
+        let events = events(&mut session);
+        assert!(events
+            .iter()
+            .any(|event| matches!(event, SessionEvent::ServerReset)));
+        for &id in &ids {
+            assert_eq!(session.state(id), Some(State::Starting(0)));
+        }
+
+        // The mappings are sent again one at a time, 50ms apart
+        let mut now = verify;
+        for port in 6000..6003u16 {
+            let request = transmit(&mut session);
+            assert_eq!(request[40..42], port.to_be_bytes());
+            assert!(session.poll_transmit().is_none());
+            if port < 6002 {
+                assert_eq!(session.poll_timeout(), Some(now + RESEND_PACE));
+                now += RESEND_PACE;
+                session.handle_timeout(now);
+            }
+        }
+        // Then the first one is retransmitted
+        assert!(session.poll_timeout().unwrap() > verify + Duration::from_secs(2));
+    }
+
+    #[test]
+    fn announce_retransmitted() {
+        let mut session = PcpSession::new(CLIENT);
+        let mut now = Instant::now();
+        session.announce(now);
+        let mut sent = 1;
+        transmit(&mut session);
+        while let Some(timeout) = session.poll_timeout() {
+            assert!(session.poll_event().is_none());
+            now = timeout;
+            session.handle_timeout(now);
+            sent += std::iter::from_fn(|| session.poll_transmit()).count();
+        }
+        assert_eq!(sent, ANNOUNCE_MRC);
+        assert!(matches!(
+            events(&mut session)[..],
+            [SessionEvent::Announced(None)]
+        ));
+    }
+
+    #[test]
+    fn epoch_backwards() {
+        let mut session = PcpSession::new(CLIENT);
+        let start = Instant::now();
+        let id = running(&mut session, 6000, start);
+        session.announce(start);
+        let request = transmit(&mut session);
+
+        let now = start + Duration::from_secs(10);
+        session
+            .handle_datagram(0, &reply(&request, ResultCode::Success, 5, 0), now)
+            .unwrap();
+        assert_eq!(session.epoch(), Some(5));
+        assert!(events(&mut session)
+            .iter()
+            .any(|event| matches!(event, SessionEvent::ServerReset)));
+        assert_eq!(session.state(id), Some(State::Starting(0)));
+        assert_eq!(transmit(&mut session)[40..42], 6000u16.to_be_bytes());
+    }
+
+    #[test]
+    fn many_mappings() {
+        let mut session = PcpSession::new(CLIENT);
+        let now = Instant::now();
+        let ids: Vec<_> = (1000..1300)
+            .map(|port| {
+                let map = InboundMap::new(port, 120).protocol(ProtocolNumber::Tcp);
+                session.request_inbound(map, RequestType::Once, now)
+            })
+            .collect();
+        let requests: Vec<_> = std::iter::from_fn(|| session.poll_transmit())
+            .map(|(_, request)| request)
+            .collect();
+        let nonces: std::collections::HashSet<_> = requests
+            .iter()
+            .map(|request| request[24..36].to_vec())
+            .collect();
+        assert_eq!(nonces.len(), ids.len());
+
+        // Every response finds its own mapping, whatever the order
+        for (request, &id) in requests.iter().zip(&ids).rev() {
+            let response = reply(request, ResultCode::Success, EPOCH, 7000);
+            session.handle_datagram(0, &response, now).unwrap();
+            assert_eq!(session.state(id), Some(State::Running));
+        }
+        assert!(!events(&mut session)
+            .iter()
+            .any(|event| matches!(event, SessionEvent::Unmatched(_))));
+        // Removing and adding mappings doesn't mix up their ids
+        for &id in &ids[..10] {
+            session.remove(id, now);
+            let request = transmit(&mut session);
+            let response = reply(&request, ResultCode::Success, EPOCH, 0);
+            session.handle_datagram(0, &response, now).unwrap();
+            assert_eq!(session.state(id), None);
+        }
+        let map = InboundMap::new(2000, 120).protocol(ProtocolNumber::Tcp);
+        let new = session.request_inbound(map, RequestType::Once, now);
+        assert!(!ids.contains(&new));
+        assert_eq!(session.len(), ids.len() - 9);
+        assert_eq!(session.state(ids[0]), None);
+    }
+
+    #[test]
+    fn failover() {
+        let mut session = PcpSession::new(CLIENT);
+        session.add_server(CLIENT);
+        let mut now = Instant::now();
+        let map = InboundMap::new(6000, 120).protocol(ProtocolNumber::Tcp);
+        let id = session.request_inbound(map, RequestType::Once, now);
+
+        // The first server doesn't answer: after the request and FAILOVER_COUNT - 1
+        // retransmissions, the next one goes to the second server
+        let mut servers = Vec::new();
+        while servers.len() <= FAILOVER_COUNT {
+            servers.extend(std::iter::from_fn(|| session.poll_transmit()).map(|(s, _)| s));
+            now = session.poll_timeout().unwrap();
+            session.handle_timeout(now);
+        }
+        assert_eq!(servers[..FAILOVER_COUNT], [0; FAILOVER_COUNT]);
+        assert_eq!(servers[FAILOVER_COUNT], 1);
+        assert_eq!(session.server(), 1);
+
+        // which answers and keeps being used
+        let (server, request) = session.poll_transmit().unwrap();
+        assert_eq!(server, 1);
+        let response = reply(&request, ResultCode::Success, EPOCH, 7000);
+        session.handle_datagram(1, &response, now).unwrap();
+        assert_eq!(session.state(id), Some(State::Running));
+        let map = InboundMap::new(6001, 120).protocol(ProtocolNumber::Tcp);
+        session.request_inbound(map, RequestType::Once, now);
+        for _ in 0..2 * FAILOVER_COUNT {
+            let transmits: Vec<_> = std::iter::from_fn(|| session.poll_transmit()).collect();
+            assert!(transmits.iter().all(|&(server, _)| server == 1));
+            now = session.poll_timeout().unwrap();
+            session.handle_timeout(now);
+        }
+    }
+
+    #[test]
+    fn server_that_answered_kept() {
+        let mut session = PcpSession::new(CLIENT);
+        session.add_server(CLIENT);
+        let start = Instant::now();
+        running(&mut session, 6000, start);
+
+        // A response from the other server is ignored, even if it matches a request
+        let map = InboundMap::new(6001, 120).protocol(ProtocolNumber::Tcp);
+        let id = session.request_inbound(map, RequestType::Once, start);
+        let request = transmit(&mut session);
+        events(&mut session);
+        let response = reply(&request, ResultCode::Success, EPOCH, 7001);
+        session.handle_datagram(1, &response, start).unwrap();
+        assert_eq!(session.state(id), Some(State::Starting(0)));
+        assert!(events(&mut session).is_empty());
+        assert_eq!(session.server(), 0);
+
+        // and so is the other server after many retransmissions (before the first mapping
+        // expires)
+        let mut now = start;
+        for n in 1..=FAILOVER_COUNT + 1 {
+            now = session.poll_timeout().unwrap();
+            session.handle_timeout(now);
+            transmit(&mut session);
+            assert_eq!(session.state(id), Some(State::Starting(n)));
+        }
+        let response = reply(&request, ResultCode::Success, epoch(start, now), 7001);
+        session.handle_datagram(0, &response, now).unwrap();
+        assert_eq!(session.state(id), Some(State::Running));
+    }
+
+    #[test]
+    fn external_address_changed() {
+        let mut session = PcpSession::new(CLIENT);
+        let start = Instant::now();
+        let map = InboundMap::new(6000, 120).protocol(ProtocolNumber::Tcp);
+        let id = session.request_inbound(map, RequestType::Once, start);
+        let request = transmit(&mut session);
+        let response = reply(&request, ResultCode::Success, EPOCH, 7000);
+        session.handle_datagram(0, &response, start).unwrap();
+        events(&mut session);
+
+        // The server notifies the new address on its own
+        let now = start + Duration::from_secs(10);
+        let new = Ipv4Addr::new(203, 0, 113, 6);
+        let mut response = reply(&request, ResultCode::Success, epoch(start, now), 7001);
+        response[44..60].copy_from_slice(&new.to_ipv6_mapped().octets());
+        session.handle_datagram(0, &response, now).unwrap();
+        let (old, new) = (
+            SocketAddr::new(EXTERNAL.into(), 7000),
+            SocketAddr::new(new.into(), 7001),
+        );
+        let alerts = events(&mut session);
+        assert!(alerts.iter().any(|event| matches!(
+            event,
+            SessionEvent::Alert(i, Alert::ExternalAddressChanged { old: o, new: n })
+                if *i == id && *o == old && *n == new
+        )));
+        assert!(alerts.iter().any(|event| matches!(
+            event,
+            SessionEvent::Alert(i, Alert::Assigned(addr, 7001, 120))
+                if *i == id && *addr == new.ip()
+        )));
+        assert_eq!(session.state(id), Some(State::Running));
+        assert_eq!(session.stored(now)[0].assigned, Some(new));
+
+        // The same address again isn't a change
+        session.handle_datagram(0, &response, now).unwrap();
+        assert!(!events(&mut session).iter().any(|event| matches!(
+            event,
+            SessionEvent::Alert(_, Alert::ExternalAddressChanged { .. })
+        )));
+    }
+
+    #[test]
+    fn peer_assigned() {
+        let mut session = PcpSession::new(CLIENT);
+        let now = Instant::now();
+        let remote = SocketAddr::new(Ipv4Addr::new(198, 51, 100, 1).into(), 443);
+        let map = OutboundMap::new(6000, Ipv4Addr::new(198, 51, 100, 1), 443, 120)
+            .protocol(ProtocolNumber::Tcp);
+        let id = session.request_outbound(map, RequestType::Once, now);
+        let request = transmit(&mut session);
+        assert_eq!(request[1], OpCode::Peer as u8);
+
+        // The server echoes the remote address and port
+        let response = reply(&request, ResultCode::Success, EPOCH, 7000);
+        session.handle_datagram(0, &response, now).unwrap();
+        assert_eq!(session.state(id), Some(State::Running));
+        let external = SocketAddr::new(EXTERNAL.into(), 7000);
+        let alerts = events(&mut session);
+        assert!(alerts.iter().any(|event| matches!(
+            event,
+            SessionEvent::Alert(i, Alert::Assigned(addr, 7000, 120))
+                if *i == id && *addr == external.ip()
+        )));
+        let assignment = alerts.iter().find_map(|event| match event {
+            SessionEvent::Alert(i, Alert::PeerAssigned(assignment)) if *i == id => Some(assignment),
+            _ => None,
+        });
+        let assignment = assignment.expect("no PeerAssigned alert");
+        assert_eq!(assignment.lifetime, 120);
+        assert_eq!(assignment.external(), external);
+        assert_eq!(assignment.remote(), remote);
+        assert_eq!(assignment.payload.internal_port, 6000);
+        assert_eq!(assignment.payload.protocol, ProtocolNumber::Tcp);
+    }
+}
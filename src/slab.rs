@@ -0,0 +1,189 @@
+//! The storage of the mappings of a `PcpSession`.
+//!
+//! The mappings are stored in a list of slots, the slots left empty by removed
+//! mappings are reused by the new ones. Every time a slot is freed its generation
+//! is increased, and each `MappingId` contains both the index of the slot and its
+//! generation, so an id of a removed mapping will never refer to the mapping that
+//! took its place.
+
+use std::fmt;
+
+/// The identifier of a mapping
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct MappingId {
+    index: u32,
+    generation: u32,
+}
+
+impl fmt::Debug for MappingId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "MappingId({}v{})", self.index, self.generation)
+    }
+}
+
+/// A slot of the `Slab`
+struct Slot<T> {
+    generation: u32,
+    value: Option<T>,
+}
+
+/// A list of values identified by generational ids
+pub struct Slab<T> {
+    slots: Vec<Slot<T>>,
+    /// Indexes of the empty slots
+    free: Vec<u32>,
+    /// Number of values stored
+    len: usize,
+}
+
+impl<T> Default for Slab<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Slab<T> {
+    /// Creates an empty `Slab`
+    pub fn new() -> Self {
+        Self {
+            slots: Vec::new(),
+            free: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// Returns the number of values stored
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Tells if there are no values stored
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Stores the value in an empty slot (or in a new one), returning its id
+    pub fn insert(&mut self, value: T) -> MappingId {
+        self.len += 1;
+        match self.free.pop() {
+            Some(index) => {
+                let slot = &mut self.slots[index as usize];
+                slot.value = Some(value);
+                MappingId {
+                    index,
+                    generation: slot.generation,
+                }
+            }
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    value: Some(value),
+                });
+                MappingId {
+                    index: self.slots.len() as u32 - 1,
+                    generation: 0,
+                }
+            }
+        }
+    }
+
+    /// Returns a reference to the value with the specified id
+    pub fn get(&self, id: MappingId) -> Option<&T> {
+        match self.slots.get(id.index as usize) {
+            Some(slot) if slot.generation == id.generation => slot.value.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Returns a mutable reference to the value with the specified id
+    pub fn get_mut(&mut self, id: MappingId) -> Option<&mut T> {
+        match self.slots.get_mut(id.index as usize) {
+            Some(slot) if slot.generation == id.generation => slot.value.as_mut(),
+            _ => None,
+        }
+    }
+
+    /// Removes the value with the specified id, freeing its slot
+    pub fn remove(&mut self, id: MappingId) -> Option<T> {
+        match self.slots.get_mut(id.index as usize) {
+            Some(slot) if slot.generation == id.generation && slot.value.is_some() => {
+                // From now on the old ids of this slot are invalid
+                slot.generation = slot.generation.wrapping_add(1);
+                self.free.push(id.index);
+                self.len -= 1;
+                slot.value.take()
+            }
+            _ => None,
+        }
+    }
+
+    /// Returns the ids of all the values stored
+    pub fn ids(&self) -> Vec<MappingId> {
+        self.iter().map(|(id, _)| id).collect()
+    }
+
+    /// Returns an iterator over the values and their ids
+    pub fn iter(&self) -> impl Iterator<Item = (MappingId, &T)> {
+        self.slots.iter().enumerate().filter_map(|(index, slot)| {
+            slot.value.as_ref().map(|value| {
+                let id = MappingId {
+                    index: index as u32,
+                    generation: slot.generation,
+                };
+                (id, value)
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_and_remove() {
+        let mut slab = Slab::new();
+        let a = slab.insert("a");
+        let b = slab.insert("b");
+        assert_eq!(slab.len(), 2);
+        assert_eq!(slab.get(a), Some(&"a"));
+        assert_eq!(slab.remove(a), Some("a"));
+        assert_eq!(slab.remove(a), None);
+        assert_eq!(slab.len(), 1);
+        assert_eq!(slab.ids(), [b]);
+    }
+
+    #[test]
+    fn removed_id_invalid_after_reuse() {
+        let mut slab = Slab::new();
+        let old = slab.insert("old");
+        slab.remove(old);
+        let new = slab.insert("new");
+        // The slot is reused, with another generation
+        assert_eq!(new.index, old.index);
+        assert_ne!(new, old);
+        assert_eq!(slab.get(old), None);
+        assert_eq!(slab.get_mut(old), None);
+        assert_eq!(slab.remove(old), None);
+        assert_eq!(slab.get(new), Some(&"new"));
+        assert_eq!(slab.len(), 1);
+    }
+
+    #[test]
+    fn more_than_256_values() {
+        let mut slab = Slab::new();
+        let ids: Vec<_> = (0..300).map(|i| slab.insert(i)).collect();
+        for (i, &id) in ids.iter().enumerate() {
+            assert_eq!(slab.get(id), Some(&i));
+        }
+        // Every slot is freed and reused many times
+        for round in 1..300 {
+            let id = ids[round % ids.len()];
+            let value = slab.remove(id).unwrap();
+            let new = slab.insert(value);
+            assert_eq!(slab.get(id), None);
+            assert_eq!(slab.get(new), Some(&value));
+        }
+        assert_eq!(slab.len(), 300);
+    }
+}
@@ -1,11 +1,18 @@
-use super::event::{Delay, Event};
+use super::channel;
+use super::event::Event;
 use super::handle::RequestType;
+use super::slab::MappingId;
 use super::IpAddress;
+use crate::types::payloads::{PeerResponsePayload, RequestPayload};
 use crate::types::{RequestPacket, ResultCode};
-use std::net::IpAddr;
+use futures_core::Stream;
+use std::future::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
 use std::sync::mpsc::{self, RecvError};
 use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
 
 // TODO: do I need AtomicState if I send an Alert?
 
@@ -32,9 +39,49 @@ impl AtomicState {
 
 /// A notitification sent when the state of a mapping changes
 /// or when the external address selected by the server is recieved
+#[derive(Debug)]
 pub enum Alert {
     StateChange,
     Assigned(IpAddr, u16, u32),
+    /// The server lost its state (e.g. it rebooted), the mapping is being requested again
+    ServerReset,
+    /// The external address (or port) assigned to the mapping has changed, the server either
+    /// notified it on its own or assigned a different one when the mapping was renewed
+    ExternalAddressChanged {
+        old: SocketAddr,
+        new: SocketAddr,
+    },
+    /// The address of the client has changed (e.g. the host moved to another network), the
+    /// mapping is being requested again from the new one
+    ClientAddressChanged {
+        old: IpAddr,
+        new: IpAddr,
+    },
+    /// The server assigned an external address and port to the outbound (PEER) mapping, this is
+    /// notified right after the `Assigned` alert and contains the whole response
+    PeerAssigned(PeerAssignment),
+}
+
+/// The response of the server to an outbound (PEER) mapping (see `Alert::PeerAssigned`)
+#[derive(PartialEq, Debug)]
+pub struct PeerAssignment {
+    /// Lifetime assigned to the mapping
+    pub lifetime: u32,
+    /// Payload of the response, containing the external address and port assigned and the remote
+    /// ones echoed by the server
+    pub payload: PeerResponsePayload,
+}
+
+impl PeerAssignment {
+    /// Returns the external address and port assigned by the server
+    pub fn external(&self) -> SocketAddr {
+        SocketAddr::new(self.payload.external_address, self.payload.external_port)
+    }
+
+    /// Returns the address and port of the remote peer, as echoed by the server
+    pub fn remote(&self) -> SocketAddr {
+        SocketAddr::new(self.payload.remote_address, self.payload.remote_port)
+    }
 }
 
 /// The state of a mapping
@@ -53,80 +100,127 @@ pub enum State {
     Error(ResultCode),
     /// The lifetime has ended
     Expired,
-    /// The map has been revoked
+    /// The request to delete the mapping (with a lifetime of 0) has been retransmitted for the
+    /// Nth time, the value is N
+    Deleting(usize),
+    /// The server confirmed that the map has been revoked
     Revoked,
-    /// The map has been dropped
-    Dropped,
+    /// The map has been dropped and its deletion has been retransmitted for the Nth time, the
+    /// value is N
+    Dropped(usize),
 }
 
 // TODO: non usare un Option<Vec<u8>> ma trova un modo di non dover reallocare ogni volta
 
 /// Represents the current state of a mapping and its data
 pub struct MappingState {
-    /// Channel used to send alerts to the handle
-    to_handle: mpsc::Sender<Alert>,
-    state: Arc<AtomicState>,
-    pub delay: Delay,
+    pub state: State,
+    /// Last retransmission time used
+    pub rt: Duration,
+    /// When the request was first sent, in the current exchange with the server
+    pub sent: Instant,
+    /// When the server assigned the current lifetime
+    pub granted: Instant,
     /// Request data with the filed parsed
     pub request: RequestPacket,
     /// Request data as a `Vec<u8>`
     pub buffer: Option<Vec<u8>>,
     /// Type of request
     pub kind: RequestType,
+    /// External address and port last assigned by the server
+    pub assigned: Option<SocketAddr>,
+    /// When the mapping expires on the server, after which there is no need to delete it
+    pub expires: Instant,
 }
 
 impl MappingState {
-    pub fn new(
-        to_handle: mpsc::Sender<Alert>,
-        state: Arc<AtomicState>,
-        request: RequestPacket,
-        delay: Delay,
-        buffer: Option<Vec<u8>>,
-        kind: RequestType,
-    ) -> Self {
+    pub fn new(request: RequestPacket, kind: RequestType, now: Instant) -> Self {
         MappingState {
-            to_handle,
-            state,
+            state: State::Requested,
+            rt: Duration::default(),
+            sent: now,
+            granted: now,
             request,
-            delay,
-            buffer,
+            buffer: None,
             kind,
+            assigned: None,
+            expires: now,
         }
     }
 
-    /// Sets the state of the mapping an alerts the handle of a state change
-    pub fn set_state(&self, state: State) {
-        self.state.set(state);
-        self.to_handle.send(Alert::StateChange).ok();
+    /// Returns the request data as a byte array, reusing the cached one if it's still valid
+    pub fn buffer(&mut self) -> Vec<u8> {
+        match self.buffer {
+            Some(ref buffer) => buffer.clone(),
+            None => {
+                let buffer = self.request.bytes();
+                self.buffer = Some(buffer.clone());
+                buffer
+            }
+        }
     }
 
-    /// Returns the state of the mapping
-    pub fn get_state(&self) -> State {
-        self.state.get()
+    /// Returns the nonce of the mapping
+    pub fn nonce(&self) -> [u8; 12] {
+        match &self.request.payload {
+            RequestPayload::Map(p) => p.nonce,
+            RequestPayload::Peer(p) => p.nonce,
+            RequestPayload::Announce => unreachable!("announce requests aren't mappings"),
+        }
+    }
+
+    /// Updates the suggested external port of a MAP request, invalidating the buffer if it
+    /// changes
+    pub fn set_external_port(&mut self, port: u16) {
+        if let RequestPayload::Map(payload) = &mut self.request.payload {
+            if payload.external_port != port {
+                payload.external_port = port;
+                self.buffer = None;
+            }
+        }
+    }
+
+    /// Updates the version of the request, invalidating the buffer if it changes
+    pub fn set_version(&mut self, version: u8) {
+        if self.request.header.version != version {
+            self.request.header.version = version;
+            self.buffer = None;
+        }
     }
 
-    /// Sends an alert to the handle
-    pub fn alert(&self, alert: Alert) {
-        self.to_handle.send(alert).ok();
+    /// Updates the address of the client in the request, invalidating the buffer if it changes
+    pub fn set_address(&mut self, address: IpAddr) {
+        if self.request.header.address != address {
+            self.request.header.address = address;
+            self.buffer = None;
+        }
+    }
+
+    /// Updates the lifetime of the request, invalidating the buffer if it changes
+    pub fn set_lifetime(&mut self, lifetime: u32) {
+        if self.request.header.lifetime != lifetime {
+            self.request.header.lifetime = lifetime;
+            self.buffer = None;
+        }
     }
 }
 
 /// An handle to a requested mapping
 pub struct MapHandle<Ip: IpAddress> {
     state: Arc<AtomicState>,
-    id: usize,
+    id: MappingId,
     /// Channel used to send instructions to the PCP client thread
     to_client: mpsc::Sender<Event<Ip>>,
     /// Channel used to receive alerts from the PCP client thread
-    from_client: mpsc::Receiver<Alert>,
+    from_client: channel::Receiver<Alert>,
 }
 
 impl<Ip: IpAddress> MapHandle<Ip> {
     pub(crate) fn new(
-        id: usize,
+        id: MappingId,
         state: Arc<AtomicState>,
         to_client: mpsc::Sender<Event<Ip>>,
-        from_client: mpsc::Receiver<Alert>,
+        from_client: channel::Receiver<Alert>,
     ) -> Self {
         Self {
             id,
@@ -136,6 +230,11 @@ impl<Ip: IpAddress> MapHandle<Ip> {
         }
     }
 
+    /// Returns the id of the mapping
+    pub fn id(&self) -> MappingId {
+        self.id
+    }
+
     /// Returns the state of the mapping
     pub fn state(&self) -> State {
         self.state.get()
@@ -146,7 +245,8 @@ impl<Ip: IpAddress> MapHandle<Ip> {
         self.to_client.send(Event::Renew(self.id, lifetime)).ok();
     }
 
-    /// Requests to revoke the mapping
+    /// Requests to revoke the mapping, which is `Deleting` until the server confirms it
+    /// (`Revoked`) or refuses it (`Error`)
     pub fn revoke(&self) {
         self.to_client.send(Event::Revoke(self.id)).ok();
     }
@@ -160,6 +260,68 @@ impl<Ip: IpAddress> MapHandle<Ip> {
     pub fn poll_alert(&self) -> Option<Alert> {
         self.from_client.try_recv().ok()
     }
+
+    /// Returns a `Stream` of the alerts received, which ends once the client has stopped
+    pub fn alerts(&self) -> Alerts<'_> {
+        Alerts(&self.from_client)
+    }
+
+    /// Returns a `Future` that resolves once the server has assigned an external address and
+    /// port to the mapping, the alerts received in the meantime are discarded
+    pub fn assigned(&self) -> Assigned<'_> {
+        Assigned {
+            state: &self.state,
+            alerts: &self.from_client,
+        }
+    }
+}
+
+/// A `Stream` of the alerts of a mapping (see `MapHandle::alerts`)
+pub struct Alerts<'a>(&'a channel::Receiver<Alert>);
+
+impl Stream for Alerts<'_> {
+    type Item = Alert;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Alert>> {
+        self.0.poll_recv(cx)
+    }
+}
+
+/// A `Future` that resolves on the first `Alert::Assigned` of a mapping (see
+/// `MapHandle::assigned`).
+///
+/// The output contains the assigned address, port and lifetime, or the state of the
+/// mapping if it ended before being assigned
+pub struct Assigned<'a> {
+    state: &'a AtomicState,
+    alerts: &'a channel::Receiver<Alert>,
+}
+
+impl Future for Assigned<'_> {
+    type Output = Result<(IpAddr, u16, u32), State>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        loop {
+            match self.alerts.poll_recv(cx) {
+                Poll::Ready(Some(Alert::Assigned(addr, port, lifetime))) => {
+                    return Poll::Ready(Ok((addr, port, lifetime)))
+                }
+                Poll::Ready(Some(_)) => (),
+                // The client has stopped
+                Poll::Ready(None) => return Poll::Ready(Err(self.state.get())),
+                // The mapping might have ended already, with the alerts taken by someone else
+                Poll::Pending => {
+                    return match self.state.get() {
+                        state @ State::Error(_)
+                        | state @ State::Expired
+                        | state @ State::Revoked
+                        | state @ State::Dropped(_) => Poll::Ready(Err(state)),
+                        _ => Poll::Pending,
+                    }
+                }
+            }
+        }
+    }
 }
 
 impl<Ip: IpAddress> Drop for MapHandle<Ip> {
@@ -167,3 +329,65 @@ impl<Ip: IpAddress> Drop for MapHandle<Ip> {
         self.to_client.send(Event::Drop(self.id)).ok();
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::task::Waker;
+
+    fn poll(assigned: &mut Assigned<'_>) -> Poll<Result<(IpAddr, u16, u32), State>> {
+        Pin::new(assigned).poll(&mut Context::from_waker(Waker::noop()))
+    }
+
+    #[test]
+    fn assigned_after_the_alerts_have_been_taken() {
+        let (tx, rx) = channel::channel();
+        let state = AtomicState::new(State::Running);
+        tx.send(Alert::StateChange).unwrap();
+        state.set(State::Expired);
+        // The alert is taken before the future is polled
+        rx.try_recv().unwrap();
+        let mut assigned = Assigned {
+            state: &state,
+            alerts: &rx,
+        };
+        assert_eq!(poll(&mut assigned), Poll::Ready(Err(State::Expired)));
+    }
+
+    #[test]
+    fn assigned_skips_the_other_alerts() {
+        let (tx, rx) = channel::channel();
+        let state = AtomicState::new(State::Running);
+        let mut assigned = Assigned {
+            state: &state,
+            alerts: &rx,
+        };
+        assert_eq!(poll(&mut assigned), Poll::Pending);
+        tx.send(Alert::StateChange).unwrap();
+        tx.send(Alert::Assigned([1, 2, 3, 4].into(), 6000, 120))
+            .unwrap();
+        assert_eq!(
+            poll(&mut assigned),
+            Poll::Ready(Ok(([1, 2, 3, 4].into(), 6000, 120)))
+        );
+    }
+
+    #[test]
+    fn assigned_before_expiring() {
+        let (tx, rx) = channel::channel();
+        let state = AtomicState::new(State::Running);
+        // The mapping was assigned, and it expired before the future was polled
+        tx.send(Alert::Assigned([1, 2, 3, 4].into(), 6000, 120))
+            .unwrap();
+        tx.send(Alert::StateChange).unwrap();
+        state.set(State::Expired);
+        let mut assigned = Assigned {
+            state: &state,
+            alerts: &rx,
+        };
+        assert_eq!(
+            poll(&mut assigned),
+            Poll::Ready(Ok(([1, 2, 3, 4].into(), 6000, 120)))
+        );
+    }
+}
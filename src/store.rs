@@ -0,0 +1,446 @@
+//! The persistence of the mappings of a client across restarts.
+//!
+//! A mapping can be refreshed or deleted only by presenting its nonce, so when
+//! the process of the client restarts (and the nonces generated are lost) the
+//! mappings it requested linger on the server until they expire, and requesting
+//! them again fails as their ports are still taken. A `StateStore` saves the
+//! active mappings in a file, each with its nonce, the parameters requested, the
+//! address assigned and when it expires, so that the next run can request them
+//! again with the same nonce (refreshing them) or delete them.
+//!
+//! The file is made of a line for each mapping: the type of the mapping (`map` or
+//! `peer`), how it's kept alive (`once`, `repeat-N` or `keep-alive`) and then its
+//! fields as `key=value`:
+//!
+//! ```text
+//! map keep-alive expires=1700000000 nonce=0f1e2d3c4b5a69788796a5b4 port=6000 protocol=6 lifetime=120 assigned=203.0.113.5:6000
+//! peer once expires=1700000100 nonce=00112233445566778899aabb port=7000 lifetime=60 remote=198.51.100.7:443
+//! ```
+//!
+//! The expiration is in seconds since the UNIX epoch, the mappings that have
+//! already expired aren't loaded. The file is written to a temporary file first,
+//! which then replaces it, so it's never left half written, and each client needs
+//! its own file as it's rewritten with only its mappings.
+//!
+//! The `Client` and the `AsyncClient` use it when built with
+//! `ClientBuilder::state_file`, a `PcpSession` can be saved with `stored`.
+
+use super::handle::RequestType;
+use super::map::{Filter, InboundMap, Mapping, OutboundMap};
+use super::session::PcpSession;
+use super::IpAddress;
+use crate::types::ProtocolNumber;
+use std::convert::TryFrom;
+use std::fmt::Write;
+use std::fs::File;
+use std::io::{self, Write as _};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Half a second, to round the expiration times
+const HALF_SECOND: Duration = Duration::from_millis(500);
+
+/// A mapping saved by a `StateStore`
+#[derive(Clone, PartialEq, Debug)]
+pub struct StoredMapping<Ip: IpAddress> {
+    /// The mapping, with its nonce and the lifetime last requested
+    pub mapping: Mapping<Ip>,
+    /// How the mapping is kept alive
+    pub kind: RequestType,
+    /// External address and port last assigned by the server, if any
+    pub assigned: Option<SocketAddr>,
+    /// Time left before the mapping expires on the server
+    pub remaining: Duration,
+}
+
+/// A file where the mappings of a client are saved
+///
+/// # Examples
+///
+/// Request again the mappings of the previous run, and delete the ones that aren't needed
+/// anymore:
+/**
+
+    let handle = ClientBuilder::new(client, server)
+        .state_file("/var/lib/pcp/mappings")
+        .start()
+        .unwrap();
+
+    let mut previous = handle.restore().unwrap();
+    // Dropping the handle of a mapping deletes it
+    previous.retain(|map_handle| map_handle.state() != State::Expired);
+
+*/
+#[derive(Clone, Debug)]
+pub struct StateStore {
+    path: PathBuf,
+}
+
+impl StateStore {
+    /// Creates a store that saves the mappings in the file at the specified path
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// Returns the path of the file
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Loads the mappings that haven't expired yet, if the file doesn't exist there are none
+    pub fn load<Ip: IpAddress>(&self) -> io::Result<Vec<StoredMapping<Ip>>> {
+        let content = match std::fs::read_to_string(&self.path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+        let now = SystemTime::now();
+        let mut mappings = Vec::new();
+        for (number, line) in content.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (mut stored, expires) = parse(line).ok_or_else(|| {
+                let msg = format!("{}:{}: invalid mapping", self.path.display(), number + 1);
+                io::Error::new(io::ErrorKind::InvalidData, msg)
+            })?;
+            // Otherwise the server has already forgotten it
+            if let Ok(remaining) = expires.duration_since(now) {
+                stored.remaining = remaining;
+                mappings.push(stored);
+            }
+        }
+        Ok(mappings)
+    }
+
+    /// Replaces the content of the file with the specified mappings
+    pub fn save<Ip: IpAddress>(&self, mappings: &[StoredMapping<Ip>]) -> io::Result<()> {
+        let now = SystemTime::now();
+        let mut content = String::new();
+        for stored in mappings {
+            format(&mut content, stored, now + stored.remaining);
+        }
+        let mut temporary = self.path.clone().into_os_string();
+        temporary.push(".tmp");
+        let mut file = File::create(&temporary)?;
+        file.write_all(content.as_bytes())?;
+        // Otherwise after a crash the rename might be persisted before the content
+        file.sync_all()?;
+        std::fs::rename(&temporary, &self.path)
+    }
+}
+
+/// Writes the line of the mapping, which expires at the specified time
+fn format<Ip: IpAddress>(out: &mut String, stored: &StoredMapping<Ip>, expires: SystemTime) {
+    let (opcode, nonce, port, protocol, lifetime) = match &stored.mapping {
+        Mapping::Inbound(m) => ("map", m.nonce, m.internal_port, m.protocol, m.lifetime),
+        Mapping::Outbound(m) => ("peer", m.nonce, m.internal_port, m.protocol, m.lifetime),
+    };
+    let kind = match stored.kind {
+        RequestType::Once => "once".to_owned(),
+        RequestType::Repeat(n) => format!("repeat-{}", n),
+        RequestType::KeepAlive => "keep-alive".to_owned(),
+    };
+    // Rounded to the nearest second, as the two clocks the expiration is computed with drift
+    // apart a little every time it's loaded and saved again
+    let expires = expires.duration_since(UNIX_EPOCH).unwrap_or_default() + HALF_SECOND;
+    write!(out, "{} {} expires={}", opcode, kind, expires.as_secs()).unwrap();
+    if let Some(nonce) = nonce {
+        out.push_str(" nonce=");
+        nonce.iter().for_each(|b| write!(out, "{:02x}", b).unwrap());
+    }
+    write!(out, " port={}", port).unwrap();
+    if let Some(protocol) = protocol {
+        write!(out, " protocol={}", protocol as u8).unwrap();
+    }
+    write!(out, " lifetime={}", lifetime).unwrap();
+    let (external_addr, external_port, third_party) = match &stored.mapping {
+        Mapping::Inbound(m) => (m.external_addr, m.external_port, m.third_party),
+        Mapping::Outbound(m) => (m.external_addr, m.external_port, m.third_party),
+    };
+    if let Some(addr) = external_addr {
+        write!(out, " external-address={}", addr.into()).unwrap();
+    }
+    if let Some(port) = external_port {
+        write!(out, " external-port={}", port).unwrap();
+    }
+    if let Some(addr) = third_party {
+        write!(out, " third-party={}", addr.into()).unwrap();
+    }
+    match &stored.mapping {
+        Mapping::Inbound(m) => {
+            for f in &m.filters {
+                let remote = SocketAddr::new(f.remote_addr.into(), f.remote_port);
+                write!(out, " filter={}/{}", remote, f.prefix).unwrap();
+            }
+            if m.prefer_failure {
+                out.push_str(" prefer-failure");
+            }
+        }
+        Mapping::Outbound(m) => {
+            let remote = SocketAddr::new(m.remote_addr.into(), m.remote_port);
+            write!(out, " remote={}", remote).unwrap();
+        }
+    }
+    if let Some(assigned) = stored.assigned {
+        write!(out, " assigned={}", assigned).unwrap();
+    }
+    out.push('\n');
+}
+
+/// Parses the line of a mapping, returning it with the time it expires at. The remaining time
+/// of the mapping returned is left to zero
+fn parse<Ip: IpAddress>(line: &str) -> Option<(StoredMapping<Ip>, SystemTime)> {
+    let mut tokens = line.split_whitespace();
+    let opcode = tokens.next()?;
+    let kind = match tokens.next()? {
+        "once" => RequestType::Once,
+        "keep-alive" => RequestType::KeepAlive,
+        kind => RequestType::Repeat(kind.strip_prefix("repeat-")?.parse().ok()?),
+    };
+    let address = |value: &str| Ip::from_ip(value.parse::<IpAddr>().ok()?);
+    let (mut expires, mut nonce, mut port, mut protocol, mut lifetime) =
+        (None, None, None, None, 0);
+    let (mut external_addr, mut external_port, mut third_party) = (None, None, None);
+    let (mut filters, mut prefer_failure, mut remote, mut assigned) =
+        (Vec::new(), false, None, None);
+    for token in tokens {
+        let (key, value) = token.split_once('=').unwrap_or((token, ""));
+        match key {
+            "expires" => expires = Some(Duration::from_secs(value.parse().ok()?)),
+            "nonce" => nonce = Some(parse_nonce(value)?),
+            "port" => port = Some(value.parse().ok()?),
+            "protocol" => {
+                protocol = Some(ProtocolNumber::try_from(value.parse::<u8>().ok()?).ok()?)
+            }
+            "lifetime" => lifetime = value.parse().ok()?,
+            "external-address" => external_addr = Some(address(value)?),
+            "external-port" => external_port = Some(value.parse().ok()?),
+            "third-party" => third_party = Some(address(value)?),
+            "filter" => {
+                let (remote, prefix) = value.rsplit_once('/')?;
+                let remote: SocketAddr = remote.parse().ok()?;
+                let prefix = prefix.parse().ok().filter(|&prefix| prefix <= Ip::LENGTH)?;
+                filters.push(Filter {
+                    remote_port: remote.port(),
+                    remote_addr: Ip::from_ip(remote.ip())?,
+                    prefix,
+                });
+            }
+            "prefer-failure" => prefer_failure = true,
+            "remote" => remote = Some(value.parse::<SocketAddr>().ok()?),
+            "assigned" => assigned = Some(value.parse().ok()?),
+            _ => return None,
+        }
+    }
+    let (nonce, internal_port) = (Some(nonce?), port?);
+    let mapping = match opcode {
+        "map" => Mapping::Inbound(InboundMap {
+            lifetime,
+            internal_port,
+            protocol,
+            third_party,
+            external_port,
+            external_addr,
+            filters,
+            prefer_failure,
+            nonce,
+        }),
+        "peer" => {
+            let remote = remote?;
+            Mapping::Outbound(OutboundMap {
+                lifetime,
+                internal_port,
+                remote_addr: Ip::from_ip(remote.ip())?,
+                remote_port: remote.port(),
+                protocol,
+                third_party,
+                external_port,
+                external_addr,
+                nonce,
+            })
+        }
+        _ => return None,
+    };
+    let stored = StoredMapping {
+        mapping,
+        kind,
+        assigned,
+        remaining: Duration::default(),
+    };
+    Some((stored, UNIX_EPOCH + expires?))
+}
+
+/// Parses a nonce written as 24 hexadecimal digits
+fn parse_nonce(value: &str) -> Option<[u8; 12]> {
+    let mut nonce = [0; 12];
+    if value.len() != 2 * nonce.len() {
+        return None;
+    }
+    for (i, byte) in nonce.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(value.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(nonce)
+}
+
+/// The persistence of the mappings of a client (either the `Client` or the `AsyncClient`):
+/// the ones of the session are saved whenever they change, along with the ones left by the
+/// previous run until they are restored (or they expire)
+pub(crate) struct Persistence<Ip: IpAddress> {
+    store: StateStore,
+    /// Mappings of the previous run that haven't been restored yet, with when they expire
+    previous: Vec<(Instant, StoredMapping<Ip>)>,
+    /// Mappings of the session last saved, with when they expire (`None` if they already have)
+    saved: Vec<(Option<Instant>, StoredMapping<Ip>)>,
+    /// Whether the mappings of the previous run have changed since they were last saved
+    restored: bool,
+}
+
+impl<Ip: IpAddress> Persistence<Ip> {
+    /// Loads the mappings of the previous run from the store
+    pub fn open(store: StateStore) -> io::Result<Self> {
+        let now = Instant::now();
+        let previous = store
+            .load()?
+            .into_iter()
+            .map(|stored: StoredMapping<Ip>| (now + stored.remaining, stored))
+            .collect();
+        Ok(Self {
+            store,
+            previous,
+            saved: Vec::new(),
+            restored: false,
+        })
+    }
+
+    /// Takes the mappings of the previous run that haven't expired yet, so that they are
+    /// requested again
+    pub fn restore(&mut self, now: Instant) -> Vec<StoredMapping<Ip>> {
+        self.restored = true;
+        self.previous
+            .drain(..)
+            .filter(|&(expires, _)| expires > now)
+            .map(|(expires, mut stored)| {
+                stored.remaining = expires - now;
+                stored
+            })
+            .collect()
+    }
+
+    /// Saves the mappings of the session and the ones of the previous run, if they have changed:
+    /// a mapping has been added or removed, or its nonce, lifetime, expiration or assigned
+    /// address is different
+    pub fn save(&mut self, session: &PcpSession<Ip>, now: Instant) -> io::Result<()> {
+        let current: Vec<_> = session
+            .stored(now)
+            .into_iter()
+            .map(|stored| {
+                let expires = Some(now + stored.remaining).filter(|&expires| expires > now);
+                let stored = StoredMapping {
+                    remaining: Duration::default(),
+                    ..stored
+                };
+                (expires, stored)
+            })
+            .collect();
+        if !self.restored && current == self.saved {
+            return Ok(());
+        }
+        self.restored = false;
+        self.saved = current;
+        self.previous.retain(|&(expires, _)| expires > now);
+        let session = self.saved.iter().map(|(expires, stored)| StoredMapping {
+            remaining: expires.map_or_else(Duration::default, |expires| expires - now),
+            ..stored.clone()
+        });
+        let previous = self.previous.iter().map(|(expires, stored)| StoredMapping {
+            remaining: *expires - now,
+            ..stored.clone()
+        });
+        self.store
+            .save(&session.chain(previous).collect::<Vec<_>>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const PREVIOUS: &str = "map keep-alive expires=4000000000 nonce=0f1e2d3c4b5a69788796a5b4 port=6000 protocol=6 lifetime=120 assigned=203.0.113.5:6000\n";
+
+    /// Returns a store in a file of its own, with the mappings of a previous run
+    fn store(name: &str, previous: &str) -> StateStore {
+        let path = std::env::temp_dir().join(format!("pcp-{}-{}", name, std::process::id()));
+        std::fs::write(&path, previous).unwrap();
+        StateStore::new(path)
+    }
+
+    fn session() -> PcpSession<Ipv4Addr> {
+        PcpSession::new(Ipv4Addr::new(192, 168, 1, 10))
+    }
+
+    #[test]
+    fn format_and_parse() {
+        let (stored, expires) = parse::<Ipv4Addr>(PREVIOUS.trim()).unwrap();
+        let mut line = String::new();
+        format(&mut line, &stored, expires);
+        assert_eq!(line, PREVIOUS);
+    }
+
+    #[test]
+    fn previous_mappings_kept_until_restored() {
+        let store = store("previous", PREVIOUS);
+        let mut persistence = Persistence::<Ipv4Addr>::open(store.clone()).unwrap();
+        let mut session = session();
+        let now = Instant::now();
+        session.request_inbound(InboundMap::new(7000, 60), RequestType::Once, now);
+
+        persistence.save(&session, now).unwrap();
+        let saved = std::fs::read_to_string(store.path()).unwrap();
+        assert_eq!(saved.lines().count(), 2);
+        assert!(saved.ends_with(PREVIOUS));
+
+        assert_eq!(persistence.restore(now).len(), 1);
+        persistence.save(&session, now).unwrap();
+        let saved = std::fs::read_to_string(store.path()).unwrap();
+        assert_eq!(saved.lines().count(), 1);
+        assert!(saved.contains("port=7000"));
+        std::fs::remove_file(store.path()).unwrap();
+    }
+
+    #[test]
+    fn saved_only_when_changed() {
+        let store = store("changed", "");
+        let mut persistence = Persistence::<Ipv4Addr>::open(store.clone()).unwrap();
+        let mut session = session();
+        let now = Instant::now();
+        std::fs::remove_file(store.path()).unwrap();
+
+        // Nothing to save
+        persistence.save(&session, now).unwrap();
+        assert!(!store.path().exists());
+
+        let id = session.request_inbound(InboundMap::new(7000, 60), RequestType::Once, now);
+        persistence.save(&session, now).unwrap();
+        assert!(store.path().exists());
+
+        // The retransmissions don't change the mapping
+        std::fs::remove_file(store.path()).unwrap();
+        let later = now + Duration::from_secs(30);
+        let mut retransmissions = 0;
+        while let Some(deadline) = session.poll_timeout().filter(|&t| t < later) {
+            session.handle_timeout(deadline);
+            persistence.save(&session, deadline).unwrap();
+            retransmissions += 1;
+        }
+        assert!(retransmissions > 1);
+        assert!(!store.path().exists());
+
+        session.revoke(id, later);
+        persistence.save(&session, later).unwrap();
+        assert_eq!(std::fs::read_to_string(store.path()).unwrap(), "");
+        std::fs::remove_file(store.path()).unwrap();
+    }
+}
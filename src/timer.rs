@@ -0,0 +1,206 @@
+//! A single scheduler for all the timers of a `PcpSession`.
+//!
+//! Every timer is identified by a key (for example the id of a mapping) and at most
+//! one timer can be active for each key: scheduling a new timer for a key replaces
+//! the previous one.
+//!
+//! The timers are stored in a binary heap ordered by their deadline, each entry is
+//! tagged with a generation number, and only the entry whose generation matches the
+//! one currently associated to the key is valid. Canceling or replacing a timer
+//! simply forgets its generation, so the old entry becomes stale and it's discarded
+//! once it reaches the top of the heap, which means that a timer that has already
+//! been canceled can never be fired.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::hash::Hash;
+use std::time::Instant;
+
+/// A scheduler of timers identified by keys of type `K`
+#[derive(Debug)]
+pub struct Scheduler<K: Copy + Eq + Hash + Ord> {
+    /// Entries of the timers, the top one is always valid (if there is one)
+    heap: BinaryHeap<Reverse<(Instant, u64, K)>>,
+    /// Generation of the active timer of each key
+    active: HashMap<K, u64>,
+    /// Generation that will be assigned to the next timer
+    generation: u64,
+}
+
+impl<K: Copy + Eq + Hash + Ord> Default for Scheduler<K> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Copy + Eq + Hash + Ord> Scheduler<K> {
+    /// Creates an empty `Scheduler`
+    pub fn new() -> Self {
+        Self {
+            heap: BinaryHeap::new(),
+            active: HashMap::new(),
+            generation: 0,
+        }
+    }
+
+    /// Starts a timer for the key that ends at the specified instant, replacing the previous one
+    pub fn schedule(&mut self, key: K, deadline: Instant) {
+        let generation = self.generation;
+        self.generation += 1;
+        self.active.insert(key, generation);
+        self.heap.push(Reverse((deadline, generation, key)));
+        self.prune();
+    }
+
+    /// Cancels the timer of the key, if there is one
+    pub fn cancel(&mut self, key: K) {
+        if self.active.remove(&key).is_some() {
+            self.prune();
+        }
+    }
+
+    /// Tells if there is an active timer for the key
+    pub fn is_active(&self, key: K) -> bool {
+        self.active.contains_key(&key)
+    }
+
+    /// Returns the instant at which the next timer ends
+    pub fn next_deadline(&self) -> Option<Instant> {
+        self.heap.peek().map(|Reverse((deadline, _, _))| *deadline)
+    }
+
+    /// Removes and returns the key of the next timer that has ended by the specified instant
+    pub fn pop_expired(&mut self, now: Instant) -> Option<K> {
+        match self.heap.peek() {
+            Some(Reverse((deadline, _, _))) if *deadline <= now => {
+                let Reverse((_, _, key)) = self.heap.pop().unwrap();
+                self.active.remove(&key);
+                self.prune();
+                Some(key)
+            }
+            _ => None,
+        }
+    }
+
+    /// Tells if the entry is the one of the active timer of its key
+    fn is_valid(&self, generation: u64, key: &K) -> bool {
+        self.active.get(key) == Some(&generation)
+    }
+
+    /// Discards the stale entries on the top of the heap, and rebuilds the heap when most of
+    /// its entries are stale
+    fn prune(&mut self) {
+        if self.heap.len() > 2 * self.active.len() + 16 {
+            let active = &self.active;
+            self.heap
+                .retain(|Reverse((_, generation, key))| active.get(key) == Some(generation));
+        }
+        while let Some(Reverse((_, generation, key))) = self.heap.peek() {
+            if self.is_valid(*generation, key) {
+                break;
+            }
+            self.heap.pop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn secs(secs: u64) -> Duration {
+        Duration::from_secs(secs)
+    }
+
+    /// Takes all the keys of the timers that have ended by the specified instant
+    fn expired(scheduler: &mut Scheduler<u32>, now: Instant) -> Vec<u32> {
+        std::iter::from_fn(|| scheduler.pop_expired(now)).collect()
+    }
+
+    #[test]
+    fn in_order_of_deadline() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(1, now + secs(2));
+        scheduler.schedule(2, now + secs(1));
+        scheduler.schedule(3, now + secs(3));
+        assert_eq!(scheduler.next_deadline(), Some(now + secs(1)));
+        assert_eq!(expired(&mut scheduler, now + secs(2)), [2, 1]);
+        assert!(!scheduler.is_active(1));
+        assert!(scheduler.is_active(3));
+        assert_eq!(expired(&mut scheduler, now + secs(5)), [3]);
+        assert_eq!(scheduler.next_deadline(), None);
+    }
+
+    #[test]
+    fn rescheduled_fires_once() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(1, now + secs(1));
+        scheduler.schedule(1, now + secs(3));
+        assert_eq!(scheduler.next_deadline(), Some(now + secs(3)));
+        assert!(expired(&mut scheduler, now + secs(2)).is_empty());
+        assert_eq!(expired(&mut scheduler, now + secs(3)), [1]);
+        assert!(expired(&mut scheduler, now + secs(10)).is_empty());
+    }
+
+    #[test]
+    fn rescheduled_earlier() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(1, now + secs(3));
+        scheduler.schedule(1, now + secs(1));
+        assert_eq!(expired(&mut scheduler, now + secs(10)), [1]);
+    }
+
+    #[test]
+    fn canceled_never_fires() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(1, now + secs(1));
+        scheduler.schedule(2, now + secs(2));
+        scheduler.cancel(1);
+        assert!(!scheduler.is_active(1));
+        assert_eq!(scheduler.next_deadline(), Some(now + secs(2)));
+        assert_eq!(expired(&mut scheduler, now + secs(10)), [2]);
+        // Canceling again (or a key without timer) does nothing
+        scheduler.cancel(1);
+        scheduler.cancel(3);
+        assert_eq!(scheduler.next_deadline(), None);
+    }
+
+    #[test]
+    fn stale_entries_pruned() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new();
+        scheduler.schedule(1, now + secs(100));
+        // Each replacement leaves a stale entry behind
+        for i in 0..100 {
+            scheduler.schedule(2, now + secs(i));
+        }
+        assert_eq!(scheduler.active.len(), 2);
+        assert!(scheduler.heap.len() <= 2 * scheduler.active.len() + 16);
+        // The stale entries on the top are discarded
+        assert_eq!(scheduler.next_deadline(), Some(now + secs(99)));
+        assert_eq!(expired(&mut scheduler, now + secs(99)), [2]);
+        assert_eq!(scheduler.next_deadline(), Some(now + secs(100)));
+        assert_eq!(scheduler.heap.len(), 1);
+    }
+
+    #[test]
+    fn stale_generation_discarded() {
+        let now = Instant::now();
+        let mut scheduler = Scheduler::new();
+        // It keeps the stale entries from reaching the top of the heap
+        scheduler.schedule(2, now);
+        scheduler.schedule(1, now + secs(1));
+        scheduler.schedule(1, now + secs(3));
+        assert_eq!(scheduler.heap.len(), 3);
+        // The stale entry of the key isn't fired, even if its deadline has passed
+        assert_eq!(expired(&mut scheduler, now + secs(2)), [2]);
+        assert_eq!(scheduler.heap.len(), 1);
+        assert_eq!(scheduler.next_deadline(), Some(now + secs(3)));
+        assert_eq!(expired(&mut scheduler, now + secs(3)), [1]);
+    }
+}
@@ -2,7 +2,7 @@
 //!
 //! The RFC defines the following format for the option header:
 /*!
-
+```text
      0                   1                   2                   3
      0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
@@ -10,7 +10,7 @@
     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
     :                       (optional) Data                         :
     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-
+```
 */
 //! **Option Code**:
 //!     8 bits. Its most significant bit indicates if this option is
@@ -70,10 +70,18 @@ impl OptionHeader {
         }
     }
 
+    /// Constructs a new header for an option unknown to this implementation
+    pub const fn raw(code: u8, length: u16) -> Self {
+        Self {
+            code: OptionCode::Unknown(code),
+            length,
+        }
+    }
+
     /// Creates a correctly formatted byte array representing the header
     pub fn bytes(&self) -> [u8; Self::SIZE] {
         let len = self.length.to_be_bytes();
-        [self.code as u8, 0, len[0], len[1]]
+        [self.code.into(), 0, len[0], len[1]]
     }
 }
 
@@ -141,6 +149,10 @@ impl<'a> TryFrom<&'a [u8]> for OptionHeaderSlice<'a> {
                         ThirdPartyOptionPayload::SIZE,
                     ))
                 }
+                // An unknown option can be skipped only if it's optional
+                OptionCode::Unknown(code) if code < 128 => {
+                    Err(ParsingError::UnknownMandatoryOption(code))
+                }
                 // The option header is valid
                 _ => Ok(OptionHeaderSlice {
                     slice: &slice[..OptionHeader::SIZE],
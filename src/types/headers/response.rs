@@ -2,7 +2,7 @@
 //!
 //! The RFC defines the following format for the response header:
 /*!
-
+```text
      0                   1                   2                   3
      0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
@@ -22,9 +22,9 @@
     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
     :             (optional) Options                                :
     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-
+```
 */
-//! **Version**: Only version 2 is supported.
+//! **Version**: Versions 1 and 2 are supported, the version 0 is NAT-PMP.
 //!
 //! **R**: Indicates Request (0) or Response (1). All Responses MUST use 1.
 //!
@@ -147,8 +147,8 @@ impl<'a> TryFrom<&'a [u8]> for ResponseHeaderSlice<'a> {
         if slice.len() < ResponseHeader::SIZE {
             Err(ParsingError::InvalidSliceLength(ResponseHeader::SIZE))
         }
-        // Versions below 2 are not supported (0 is NAT-PMP)
-        else if slice[0] < 2 {
+        // The version 0 is NAT-PMP
+        else if slice[0] == 0 {
             Err(ParsingError::VersionNotSupported(slice[0]))
         }
         // The R field tells if the packet is a response or a request
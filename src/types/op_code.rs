@@ -23,9 +23,13 @@ impl OpCode {
             Self::Peer => &[oc::ThirdParty],
         }
     }
-    /// Checks if the provided option code is valid for this opcode
+    /// Checks if the provided option code is valid for this opcode, the unknown ones are
+    /// always considered valid as only the PCP server can tell
     pub fn valid_option(&self, option: &OptionCode) -> bool {
-        self.valid_options().iter().any(|o| o == option)
+        match option {
+            OptionCode::Unknown(_) => true,
+            _ => self.valid_options().iter().any(|o| o == option),
+        }
     }
 }
 
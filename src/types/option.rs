@@ -47,9 +47,18 @@ impl PacketOption {
             payload: OptionPayload::PreferFailure,
         }
     }
-    /// Returns the size of the option
+
+    /// Constructs an option unknown to this implementation, with the specified code and data
+    pub fn raw(code: u8, data: Vec<u8>) -> Self {
+        Self {
+            header: OptionHeader::raw(code, data.len() as u16),
+            payload: OptionPayload::Raw(data),
+        }
+    }
+
+    /// Returns the size of the option, including the padding
     pub fn size(&self) -> usize {
-        OptionHeader::SIZE + self.payload.size()
+        OptionHeader::SIZE + padded(self.payload.size())
     }
 }
 
@@ -62,9 +71,9 @@ pub struct PacketOptionSlice<'a> {
 }
 
 impl<'a> PacketOptionSlice<'a> {
-    /// Returns the size of the option
+    /// Returns the size of the option, including the padding
     pub fn size(&self) -> usize {
-        OptionHeader::SIZE + self.payload.size()
+        OptionHeader::SIZE + padded(self.payload.size())
     }
     /// Returns a reference to the payload data of the packet
     pub fn payload(&self) -> &OptionPayloadSlice<'a> {
@@ -103,7 +112,50 @@ impl<'a> TryFrom<&'a [u8]> for PacketOptionSlice<'a> {
             }
             // there is no payload, so just return the enum value
             OptionCode::PreferFailure => OptionPayloadSlice::PreferFailure,
+            // the payload is unknown, so it's kept as it is, the padding must be there too
+            // as it's skipped along with the option
+            OptionCode::Unknown(_) => {
+                let length = header.length() as usize;
+                let end = OptionHeader::SIZE + padded(length);
+                if slice.len() < end {
+                    return Err(ParsingError::InvalidSliceLength(end));
+                }
+                OptionPayloadSlice::Raw(&slice[OptionHeader::SIZE..OptionHeader::SIZE + length])
+            }
         };
         Ok(PacketOptionSlice { header, payload })
     }
 }
+
+/// Rounds the length of the option data up to a multiple of 4, as the options are padded
+const fn padded(length: usize) -> usize {
+    (length + 3) & !3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unknown_option_with_padding() {
+        let data = [200, 0, 0, 5, 1, 2, 3, 4, 5, 0, 0, 0];
+        let option = PacketOptionSlice::try_from(&data[..]).unwrap();
+        assert_eq!(option.size(), 12);
+        assert_eq!(option.payload().slice(), &[1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn unknown_option_truncated_padding() {
+        let data = [200, 0, 0, 5, 1, 2, 3, 4, 5, 0];
+        assert!(matches!(
+            PacketOptionSlice::try_from(&data[..]),
+            Err(ParsingError::InvalidSliceLength(12))
+        ));
+    }
+
+    #[test]
+    fn unknown_option_length_past_the_end() {
+        let data = [200, 0, 1, 0, 1, 2, 3, 4];
+        assert!(PacketOptionSlice::try_from(&data[..]).is_err());
+    }
+}
@@ -3,16 +3,37 @@ use std::convert::TryFrom;
 
 /// The `OptionCode` field contained in the PCP option header (see `OptionHeader`)
 ///
-/// Currently only three option codes are defined
+/// Currently only three option codes are defined, the others are kept as `Unknown`
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum OptionCode {
     /// Indicates the MAP or PEER request is for a host other than the host sending the PCP option
-    ThirdParty = 1,
+    ThirdParty,
     /// indicates that the PCP server should not create an alternative mapping if the
     /// suggested external port and address cannot be mapped
-    PreferFailure = 2,
+    PreferFailure,
     /// specifies a filter for incoming packets
-    Filter = 3,
+    Filter,
+    /// An option code not known by this implementation
+    Unknown(u8),
+}
+
+impl OptionCode {
+    /// Tells if the option can be skipped by who doesn't know it, that is if the code is in
+    /// the range 128-255 (the ones in the range 0-127 are mandatory to process)
+    pub fn is_optional(self) -> bool {
+        u8::from(self) >= 128
+    }
+}
+
+impl From<OptionCode> for u8 {
+    fn from(code: OptionCode) -> Self {
+        match code {
+            OptionCode::ThirdParty => 1,
+            OptionCode::PreferFailure => 2,
+            OptionCode::Filter => 3,
+            OptionCode::Unknown(n) => n,
+        }
+    }
 }
 
 impl TryFrom<u8> for OptionCode {
@@ -20,10 +41,12 @@ impl TryFrom<u8> for OptionCode {
 
     fn try_from(val: u8) -> Result<Self, Self::Error> {
         match val {
+            // The option code 0 is reserved
+            0 => Err(ParsingError::NotAnOptionCode(0)),
             1 => Ok(Self::ThirdParty),
             2 => Ok(Self::PreferFailure),
             3 => Ok(Self::Filter),
-            n => Err(ParsingError::NotAnOptionCode(n)),
+            n => Ok(Self::Unknown(n)),
         }
     }
 }
@@ -8,6 +8,8 @@ pub enum ParsingError {
     NotAnOptionCode(u8),
     /// The parsed value (1st) is not a result code
     NotAResultCode(u8),
+    /// The parsed value (1st) is not a NAT-PMP result code
+    NotANatPmpResultCode(u16),
     /// The parsed value (1st) is not a protocol number
     NotAProtocolNumber(u8),
     /// The size of the value (1st) is bigger than the size of the slice
@@ -22,4 +24,6 @@ pub enum ParsingError {
     InvalidPrefix(u8),
     /// The option code (2nd) is not valid for that opcode (1st)
     InvalidOption(OpCode, OptionCode),
+    /// The option code (1st) is not known and the option is mandatory to process
+    UnknownMandatoryOption(u8),
 }
@@ -2,7 +2,7 @@
 //!
 //! The RFC defines the following format for the filter option payload:
 /*!
-
+```text
      0                   1                   2                   3
      0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
@@ -15,7 +15,7 @@
     |                                                               |
     |                                                               |
     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-
+```
 */
 //! **Reserved**:
 //! 8 reserved bits, MUST be sent as 0 and MUST be ignored when received.
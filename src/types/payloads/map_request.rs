@@ -2,7 +2,7 @@
 //!
 //! The RFC defines the following format for the map request payload:
 /*!
-
+```text
      0                   1                   2                   3
      0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
@@ -19,7 +19,7 @@
     |                                                               |
     |                                                               |
     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-
+```
 */
 //! **Requested lifetime** (in common header):
 //!     Requested lifetime of this mapping, in seconds. The value 0 indicates "delete".
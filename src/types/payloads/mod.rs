@@ -19,21 +19,24 @@ use std::net::IpAddr;
 
 /// An enum containing a PCP option payload
 ///
-/// Currently supported option payloads are: filter, third party and prefer failure.
+/// Currently supported option payloads are: filter, third party and prefer failure,
+/// the payloads of the other options are kept as they are.
 #[derive(PartialEq, Debug)]
 pub enum OptionPayload {
     Filter(FilterOptionPayload),
     ThidParty(ThirdPartyOptionPayload),
     PreferFailure,
+    Raw(Vec<u8>),
 }
 
 impl OptionPayload {
-    /// Returns the size in bytes of the payload
+    /// Returns the size in bytes of the payload (without padding)
     pub const fn size(&self) -> usize {
         match self {
             Self::Filter(_) => FilterOptionPayload::SIZE,
             Self::ThidParty(_) => ThirdPartyOptionPayload::SIZE,
             Self::PreferFailure => 0,
+            Self::Raw(data) => data.len(),
         }
     }
 
@@ -76,15 +79,17 @@ pub enum OptionPayloadSlice<'a> {
     Filter(FilterOptionPayloadSlice<'a>),
     ThidParty(ThirdPartyOptionPayloadSlice<'a>),
     PreferFailure,
+    Raw(&'a [u8]),
 }
 
 impl OptionPayloadSlice<'_> {
-    /// Returns the size in bytes of the option payload
+    /// Returns the size in bytes of the option payload (without padding)
     pub const fn size(&self) -> usize {
         match self {
             Self::Filter(_) => FilterOptionPayload::SIZE,
             Self::ThidParty(_) => ThirdPartyOptionPayload::SIZE,
             Self::PreferFailure => 0,
+            Self::Raw(data) => data.len(),
         }
     }
 
@@ -94,6 +99,7 @@ impl OptionPayloadSlice<'_> {
             Self::Filter(p) => p.slice(),
             Self::PreferFailure => &[],
             Self::ThidParty(p) => p.slice(),
+            Self::Raw(data) => data,
         }
     }
 }
@@ -106,6 +112,7 @@ impl Parsable for OptionPayloadSlice<'_> {
             Self::Filter(p) => OptionPayload::Filter(p.parse()),
             Self::ThidParty(p) => OptionPayload::ThidParty(p.parse()),
             Self::PreferFailure => OptionPayload::PreferFailure,
+            Self::Raw(data) => OptionPayload::Raw(data.to_vec()),
         }
     }
 }
@@ -202,6 +209,7 @@ impl From<PeerRequestPayload> for RequestPayload {
 /// An enum containing a PCP response payload
 ///
 /// Currently supported response payloads are: map, peer and announce.
+#[derive(PartialEq, Debug)]
 pub enum ResponsePayload {
     Map(MapResponsePayload),
     Peer(PeerResponsePayload),
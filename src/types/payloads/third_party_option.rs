@@ -2,7 +2,7 @@
 //!
 //! The RFC defines the following format for the third party option payload:
 /*!
-
+```text
      0                   1                   2                   3
      0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1 2 3 4 5 6 7 8 9 0 1
     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
@@ -13,7 +13,7 @@
     |                                                               |
     |                                                               |
     +-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+-+
-
+```
 */
 //! **Internal IP Address**: Internal IP address for this mapping.
 
@@ -56,7 +56,7 @@ pub struct ThirdPartyOptionPayloadSlice<'a> {
 impl ThirdPartyOptionPayloadSlice<'_> {
     /// Returns the address
     pub fn address(&self) -> IpAddr {
-        Ipv6Addr::from(<[u8; 16]>::try_from(&self.slice[..]).unwrap()).unmap()
+        Ipv6Addr::from(<[u8; 16]>::try_from(self.slice).unwrap()).unmap()
     }
 
     /// Returns the inner slice
@@ -13,6 +13,9 @@ use std::net::IpAddr;
 /// the check will be done once the request is submitted.
 ///
 /// There are three types of requests: `map`, `peer` and `announce`.
+///
+/// The packet is formatted according to the version in its header: the MAP and PEER
+/// payloads of the version 1 of PCP lack the mapping nonce.
 pub struct RequestPacket {
     pub header: RequestHeader,
     pub payload: RequestPayload,
@@ -20,34 +23,52 @@ pub struct RequestPacket {
 }
 
 impl RequestPacket {
+    /// Size of the mapping nonce, which is missing in the version 1 payloads (in bytes)
+    pub const NONCE_SIZE: usize = 12;
+
     /// Returns the size in bytes of the request
     pub fn size(&self) -> usize {
-        RequestHeader::SIZE
-            + self.payload.size()
+        RequestHeader::SIZE + self.payload.size() - self.skipped()
             + self.options.iter().map(PacketOption::size).sum::<usize>()
     }
 
+    /// Returns the number of bytes at the start of the payload that aren't sent because of
+    /// the version of the packet
+    fn skipped(&self) -> usize {
+        match self.payload {
+            RequestPayload::Announce => 0,
+            _ if self.header.version == 1 => Self::NONCE_SIZE,
+            _ => 0,
+        }
+    }
+
     /// Returns the byte array containing the request packet formatted correctly
     pub fn bytes(&self) -> Vec<u8> {
         let mut buf = Vec::with_capacity(self.size());
         buf.extend_from_slice(&self.header.bytes());
+        let skip = self.skipped();
         match &self.payload {
-            RequestPayload::Map(p) => buf.extend_from_slice(&p.bytes()),
-            RequestPayload::Peer(p) => buf.extend_from_slice(&p.bytes()),
+            RequestPayload::Map(p) => buf.extend_from_slice(&p.bytes()[skip..]),
+            RequestPayload::Peer(p) => buf.extend_from_slice(&p.bytes()[skip..]),
             RequestPayload::Announce => (),
         };
         self.options.iter().for_each(|o| {
+            let start = buf.len();
             buf.extend_from_slice(&o.header.bytes());
             match &o.payload {
                 OptionPayload::PreferFailure => (),
                 OptionPayload::Filter(p) => buf.extend_from_slice(&p.bytes()),
                 OptionPayload::ThidParty(p) => buf.extend_from_slice(&p.bytes()),
+                OptionPayload::Raw(data) => buf.extend_from_slice(data),
             }
+            // The options are padded to a multiple of 4 bytes
+            buf.resize(start + o.size(), 0);
         });
         buf
     }
 
     /// Constructs a PCP map request
+    #[allow(clippy::too_many_arguments)]
     pub fn map(
         version: u8,
         lifetime: u32,
@@ -87,6 +108,7 @@ impl RequestPacket {
     }
 
     /// Constructs a PCP peer request
+    #[allow(clippy::too_many_arguments)]
     pub fn peer(
         version: u8,
         lifetime: u32,
@@ -2,7 +2,9 @@ use crate::types::headers::{ResponseHeader, ResponseHeaderSlice};
 use crate::types::payloads::{
     MapResponsePayloadSlice, PeerResponsePayloadSlice, ResponsePayload, ResponsePayloadSlice,
 };
-use crate::types::{OpCode, PacketOption, PacketOptionSlice, Parsable, ParsingError};
+use crate::types::{
+    OpCode, PacketOption, PacketOptionSlice, Parsable, ParsingError, RequestPacket,
+};
 use std::convert::TryFrom;
 
 ///   A PCP `ResponsePacket` containing a `ResponseHeader`, a `ResponsePayload`
@@ -10,6 +12,7 @@ use std::convert::TryFrom;
 ///
 ///   This type cannot be constructed directly as it is meant to be received from a
 ///   UDP socket. The only way to get it is to `parse` (see `Parsable`) a `ResponsePacketSlice`.
+#[derive(PartialEq, Debug)]
 pub struct ResponsePacket {
     pub header: ResponseHeader,
     pub payload: ResponsePayload,
@@ -25,6 +28,29 @@ pub struct ResponsePacketSlice<'a> {
     options: Vec<PacketOptionSlice<'a>>,
 }
 
+impl ResponsePacket {
+    /// Parses a response of the version 1 of PCP.
+    ///
+    /// The packets of the version 1 are the same of the version 2 but the MAP and PEER
+    /// payloads lack the mapping nonce, so it can't be a zero-copy type: the data is
+    /// copied in the layout of the version 2 and the nonce of the returned packet is zeroed.
+    pub fn parse_v1(slice: &[u8]) -> Result<Self, ParsingError> {
+        let header = ResponseHeaderSlice::try_from(slice)?;
+        let mut buf = slice.to_vec();
+        buf[0] = 2;
+        if header.opcode() != OpCode::Announce {
+            let nonce = [0; RequestPacket::NONCE_SIZE];
+            buf.splice(
+                ResponseHeader::SIZE..ResponseHeader::SIZE,
+                nonce.iter().copied(),
+            );
+        }
+        let mut packet = ResponsePacketSlice::try_from(&buf[..])?.parse();
+        packet.header.version = 1;
+        Ok(packet)
+    }
+}
+
 impl<'a> ResponsePacketSlice<'a> {
     /// Returns a reference to the options in the packets
     pub const fn options(&self) -> &Vec<PacketOptionSlice<'a>> {
@@ -60,6 +86,10 @@ impl<'a> TryFrom<&'a [u8]> for ResponsePacketSlice<'a> {
     fn try_from(slice: &'a [u8]) -> Result<Self, Self::Error> {
         // Check if the header is valid
         let header = ResponseHeaderSlice::try_from(slice)?;
+        // The payloads of the version 1 are different (see `ResponsePacket::parse_v1`)
+        if header.version() == 1 && header.opcode() != OpCode::Announce {
+            return Err(ParsingError::VersionNotSupported(1));
+        }
 
         let mut at = ResponseHeader::SIZE;
 